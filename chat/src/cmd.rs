@@ -0,0 +1,182 @@
+use std::path::PathBuf;
+use net::{Room, RoomOp};
+
+/// 以':'开头的用户指令
+#[derive(Debug)]
+pub enum Cmd {
+    /// :reply <消息ID> <内容>
+    Reply { tag: String, body: String },
+    /// :dm <用户名> <内容>，只发给一个已连接的成员
+    Dm { name: String, body: String },
+    /// :status [消息ID]，查看自己发出的消息的回执
+    Status { tag: Option<String> },
+    /// :send <用户名> <文件路径>
+    Send { name: String, path: PathBuf },
+    /// :accept <文件ID>
+    Accept { tag: String },
+    /// :decline <文件ID>
+    Decline { tag: String },
+    /// :join <房间名> <密码> 或 :join <邀请码>，同时加入另一个房间
+    Join(Room),
+    /// :room [房间名|房间ID]，切换输入的消息发往的房间，不带参数时列出已加入的房间
+    Switch(Option<String>),
+    /// :leave [房间名|房间ID]，离开房间，不带参数时离开当前房间
+    Leave(Option<String>),
+    /// 房主和管理员管理房间：:kick、:ban、:unban、:passwd、:op、:deop、:owner、:topic、:desc、:capacity、:invite
+    Room(RoomOp),
+}
+
+// 邀请码默认的有效期
+const INVITE_TTL: u64 = 60 * 60;
+
+impl Cmd {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.strip_prefix(':').unwrap_or(line);
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            "reply" | "re" => {
+                let (tag, body) = args.trim().split_once(' ')
+                    .ok_or("用法：:reply <消息ID> <内容>")?;
+                Ok(Self::Reply { tag: tag.to_lowercase(), body: body.trim().to_string() })
+            },
+            "dm" => {
+                let (name, body) = args.trim().split_once(' ')
+                    .ok_or("用法：:dm <用户名> <内容>")?;
+                Ok(Self::Dm { name: name.to_string(), body: body.trim().to_string() })
+            },
+            "status" => {
+                let tag = args.split_whitespace().next().map(|t| t.to_lowercase());
+                Ok(Self::Status { tag })
+            },
+            "send" => {
+                let (name, path) = args.trim().split_once(' ')
+                    .ok_or("用法：:send <用户名> <文件路径>")?;
+                Ok(Self::Send { name: name.to_string(), path: PathBuf::from(path.trim()) })
+            },
+            "accept" | "decline" => {
+                let tag = args.split_whitespace().next()
+                    .ok_or(format!("用法：:{} <文件ID>", name))?
+                    .to_lowercase();
+                if name == "accept" {
+                    Ok(Self::Accept { tag })
+                } else {
+                    Ok(Self::Decline { tag })
+                }
+            },
+            "join" => {
+                let mut room = Room::default();
+                match args.trim().split_once(' ') {
+                    Some((name, passwd)) => {
+                        room.name = name.into();
+                        room.passwd = passwd.trim().into();
+                    },
+                    None if !args.trim().is_empty() => room.invite = args.trim().into(),
+                    None => return Err("用法：:join <房间名> <密码> 或 :join <邀请码>".into()),
+                }
+                Ok(Self::Join(room))
+            },
+            "room" | "leave" => {
+                let key = args.trim();
+                let key = (!key.is_empty()).then(|| key.to_string());
+                if name == "room" {
+                    Ok(Self::Switch(key))
+                } else {
+                    Ok(Self::Leave(key))
+                }
+            },
+            "kick" => {
+                let (user, reason) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+                if user.is_empty() {
+                    return Err("用法：:kick <用户名> [原因]".into());
+                }
+                Ok(Self::Room(RoomOp::Kick { user: user.into(), reason: reason.trim().into() }))
+            },
+            "passwd" => {
+                let passwd = args.trim();
+                if passwd.is_empty() {
+                    return Err("用法：:passwd <新密码>".into());
+                }
+                Ok(Self::Room(RoomOp::Passwd(passwd.into())))
+            },
+            // 不带内容时清除
+            "topic" => Ok(Self::Room(RoomOp::Topic(args.trim().into()))),
+            "desc" => Ok(Self::Room(RoomOp::Describe(args.trim().into()))),
+            "capacity" => {
+                let capacity = args.trim().parse()
+                    .map_err(|_| "用法：:capacity <人数>，0为不限")?;
+                Ok(Self::Room(RoomOp::Capacity(capacity)))
+            },
+            "invite" => {
+                let mut ttl = INVITE_TTL;
+                let mut once = false;
+                for arg in args.split_whitespace() {
+                    if arg == "once" {
+                        once = true;
+                    } else {
+                        ttl = net::parse_duration(arg).map(|d| d.as_secs()).ok_or("用法：:invite [有效期，如30m、2h、1d] [once]")?;
+                    }
+                }
+                Ok(Self::Room(RoomOp::Invite { ttl, once }))
+            },
+            "ban" | "unban" | "op" | "deop" | "owner" => {
+                let user = args.split_whitespace().next()
+                    .ok_or(format!("用法：:{} <用户名>", name))?
+                    .to_string();
+                Ok(Self::Room(match name {
+                    "ban" => RoomOp::Ban { user },
+                    "unban" => RoomOp::Unban { user },
+                    "op" => RoomOp::Op { user },
+                    "deop" => RoomOp::Deop { user },
+                    _ => RoomOp::Transfer { user },
+                }))
+            },
+            _ => Err(format!("未知指令：{}", name)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Cmd {
+        Cmd::parse(line).unwrap()
+    }
+
+    #[test]
+    fn messages() {
+        assert!(matches!(parse(":reply A1B2C3  hi there"), Cmd::Reply { tag, body } if tag == "a1b2c3" && body == "hi there"));
+        assert!(matches!(parse(":re abc x"), Cmd::Reply { .. }));
+        assert!(matches!(parse(":dm bob  hello"), Cmd::Dm { name, body } if name == "bob" && body == "hello"));
+        assert!(matches!(parse(":status"), Cmd::Status { tag: None }));
+        assert!(matches!(parse(":status ABC"), Cmd::Status { tag: Some(t) } if t == "abc"));
+        assert!(matches!(parse(":send bob /tmp/a b.txt"), Cmd::Send { name, path } if name == "bob" && path.to_str() == Some("/tmp/a b.txt")));
+        assert!(matches!(parse(":accept 00FF00"), Cmd::Accept { tag } if tag == "00ff00"));
+        assert!(matches!(parse(":decline 1"), Cmd::Decline { .. }));
+    }
+
+    #[test]
+    fn rooms() {
+        assert!(matches!(parse(":join room2 pw 2"), Cmd::Join(r) if r.name == "room2" && r.passwd == "pw 2".into() && r.invite.is_empty()));
+        assert!(matches!(parse(":join TOKEN"), Cmd::Join(r) if r.invite == "TOKEN".into() && r.name.is_empty()));
+        assert!(matches!(parse(":room"), Cmd::Switch(None)));
+        assert!(matches!(parse(":room room2"), Cmd::Switch(Some(k)) if k == "room2"));
+        assert!(matches!(parse(":leave"), Cmd::Leave(None)));
+        assert!(matches!(parse(":kick bob too loud"), Cmd::Room(RoomOp::Kick { user, reason }) if user == "bob" && reason == "too loud"));
+        assert!(matches!(parse(":kick bob"), Cmd::Room(RoomOp::Kick { reason, .. }) if reason.is_empty()));
+        assert!(matches!(parse(":owner bob"), Cmd::Room(RoomOp::Transfer { user }) if user == "bob"));
+        assert!(matches!(parse(":topic"), Cmd::Room(RoomOp::Topic(t)) if t.is_empty()));
+        assert!(matches!(parse(":capacity 10"), Cmd::Room(RoomOp::Capacity(10))));
+        assert!(matches!(parse(":invite"), Cmd::Room(RoomOp::Invite { ttl: INVITE_TTL, once: false })));
+        assert!(matches!(parse(":invite 30m once"), Cmd::Room(RoomOp::Invite { ttl: 1800, once: true })));
+    }
+
+    #[test]
+    fn errors() {
+        for line in [":reply abc", ":dm bob", ":send bob", ":accept", ":join", ":kick", ":passwd",
+                ":capacity x", ":invite 5x", ":ban", ":what"] {
+            assert!(Cmd::parse(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use net::ChatMsg;
+
+/// 本地保存的最近聊天记录
+/// 按照发送方时间戳排序，并通过消息ID去重
+#[derive(Debug, Default)]
+pub struct History {
+    msgs: Vec<ChatMsg>,
+    ids: HashSet<u64>,
+    cap: usize,
+}
+
+impl History {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            msgs: Vec::new(),
+            ids: HashSet::new(),
+            cap,
+        }
+    }
+
+    /// 插入一条消息，消息已存在或者比已满的记录中所有消息都旧时返回false
+    pub fn insert(&mut self, msg: ChatMsg) -> bool {
+        if self.ids.contains(&msg.id) {
+            return false;
+        }
+        // 大多数情况下消息是按顺序到达的，从后往前找插入位置
+        let pos = self.msgs.iter()
+            .rposition(|m| (m.time, m.id) <= (msg.time, msg.id))
+            .map_or(0, |p| p + 1);
+        // 插入后会马上被移除，不当作新消息
+        if pos == 0 && self.msgs.len() >= self.cap {
+            return false;
+        }
+        self.ids.insert(msg.id);
+        self.msgs.insert(pos, msg);
+        if self.msgs.len() > self.cap {
+            let old = self.msgs.remove(0);
+            self.ids.remove(&old.id);
+        }
+        true
+    }
+
+    /// 合并其他客户端发送过来的记录
+    /// 返回本地之前没有的消息（按时间排序）
+    pub fn merge(&mut self, msgs: Vec<ChatMsg>) -> Vec<ChatMsg> {
+        let mut new_msgs: Vec<ChatMsg> = msgs.into_iter()
+            .filter(|m| self.insert(m.clone()))
+            .collect();
+        new_msgs.sort_by_key(|m| (m.time, m.id));
+        new_msgs
+    }
+
+    pub fn get(&self, id: u64) -> Option<&ChatMsg> {
+        if !self.ids.contains(&id) {
+            return None;
+        }
+        self.msgs.iter().rev().find(|m| m.id == id)
+    }
+
+    /// 通过消息ID的简写查找消息，优先返回最新的
+    pub fn find_by_tag(&self, tag: &str) -> Option<&ChatMsg> {
+        self.msgs.iter().rev().find(|m| m.tag() == tag)
+    }
+
+    /// 最近的limit条消息
+    pub fn recent(&self, limit: usize) -> Vec<ChatMsg> {
+        let start = self.msgs.len().saturating_sub(limit);
+        self.msgs[start..].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use net::BaseUserInfo;
+    use super::*;
+
+    fn msg(id: u64, time: i64) -> ChatMsg {
+        ChatMsg {
+            id,
+            sender: BaseUserInfo { id: 1, name: "alice".into() },
+            time,
+            room: 1,
+            body: format!("msg{}", id),
+            reply_to: None,
+        }
+    }
+
+    fn ids(h: &History) -> Vec<u64> {
+        h.recent(usize::MAX).iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn insert_sorts_and_dedups() {
+        let mut h = History::new(10);
+        assert!(h.insert(msg(2, 20)));
+        assert!(h.insert(msg(1, 10)));
+        assert!(h.insert(msg(3, 30)));
+        assert!(!h.insert(msg(2, 20)));
+        assert_eq!(ids(&h), [1, 2, 3]);
+        assert_eq!(h.get(2).map(|m| m.time), Some(20));
+        assert!(h.get(4).is_none());
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let mut h = History::new(2);
+        h.insert(msg(1, 10));
+        h.insert(msg(2, 20));
+        assert!(h.insert(msg(3, 30)));
+        assert_eq!(ids(&h), [2, 3]);
+        // 被移除的消息可以再次插入，但比所有消息都旧时不算新消息
+        assert!(!h.insert(msg(1, 10)));
+        assert_eq!(ids(&h), [2, 3]);
+        assert!(h.get(1).is_none());
+    }
+
+    #[test]
+    fn merge_returns_only_new_in_order() {
+        let mut h = History::new(10);
+        h.insert(msg(2, 20));
+        let new = h.merge(vec![msg(3, 30), msg(2, 20), msg(1, 10)]);
+        assert_eq!(new.iter().map(|m| m.id).collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(ids(&h), [1, 2, 3]);
+    }
+
+    #[test]
+    fn find_by_tag_prefers_newest() {
+        let mut h = History::new(10);
+        // 两条消息的ID简写相同
+        h.insert(msg(0x1_000001, 10));
+        h.insert(msg(0x2_000001, 20));
+        assert_eq!(h.find_by_tag("000001").map(|m| m.id), Some(0x2_000001));
+    }
+
+    #[test]
+    fn peers_converge() {
+        // 时间戳相同的消息按ID排序，两边合并后的顺序一致
+        let mut a = History::new(10);
+        let mut b = History::new(10);
+        a.insert(msg(1, 10));
+        a.insert(msg(5, 20));
+        b.insert(msg(2, 10));
+        b.insert(msg(4, 20));
+        b.insert(msg(5, 20));
+        let from_a = a.recent(10);
+        let new = a.merge(b.recent(10));
+        assert_eq!(new.iter().map(|m| m.id).collect::<Vec<_>>(), [2, 4]);
+        assert_eq!(b.merge(from_a).len(), 1);
+        assert_eq!(ids(&a), [1, 2, 4, 5]);
+        assert_eq!(ids(&a), ids(&b));
+        // 再次交换没有新消息
+        assert!(a.merge(b.recent(10)).is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+//! 局域网模式：不需要服务端，通过UDP广播发现同一个房间的成员后直接连接
+
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use log::{debug, info, warn};
+use net::{BaseUserInfo, ClientInfo, LanAnnounce, LanHello, LanProof, Secret, ToPackage, User, ID};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{Error, ErrorKind, Result},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{interval, timeout, Interval},
+};
+use crate::peer::swap_info;
+
+/// 默认的通告地址，所有客户端都监听这个端口
+pub const DEFAULT_LAN_ADDR: &str = "255.255.255.255:5567";
+/// 局域网模式下只有一个房间，所有成员使用相同的房间ID
+pub const ROOM: ID = 0;
+// 发送通告的间隔，断开的成员也会在下一次通告时重新连接
+const ANNOUNCE: Duration = Duration::from_secs(3);
+// 连接后交换信息、确认房间的时间，连接进来后什么都不发的不能一直占着
+const HANDSHAKE: Duration = Duration::from_secs(10);
+
+pub enum Event {
+    /// 发现同一个房间中ID比自己大的成员，由自己发起连接
+    Found(ClientInfo),
+    /// 其他成员连接进来
+    Accepted(TcpStream, SocketAddr),
+}
+
+pub struct Lan {
+    announce: LanAnnounce,
+    listener: TcpListener,
+    udp: UdpSocket,
+    // 通告发往的地址，广播地址或者组播地址
+    target: SocketAddr,
+    ticker: Interval,
+    // 房间密码，为空时也要双方一致
+    key: Secret,
+    // 房间名或密码不同的成员，不再连接
+    rejected: Arc<Mutex<HashSet<ID>>>,
+    /// 正在连接的成员
+    pub pending: HashSet<ID>,
+}
+
+/// 连接双方确认在同一个房间、房间密码相同需要的信息
+#[derive(Clone)]
+pub struct RoomKey {
+    room: String,
+    key: Secret,
+    rejected: Arc<Mutex<HashSet<ID>>>,
+}
+
+impl Lan {
+    pub async fn bind(user: &User, room: String, key: Secret, target: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let udp = udp_socket(target)?;
+        Ok(Self {
+            announce: LanAnnounce {
+                room,
+                user: BaseUserInfo { id: user.id, name: user.name.clone() },
+                addr: listener.local_addr()?,
+            },
+            listener, udp, target, key,
+            rejected: Arc::default(),
+            ticker: interval(ANNOUNCE),
+            pending: HashSet::new(),
+        })
+    }
+
+    pub fn room(&self) -> &str {
+        &self.announce.room
+    }
+
+    pub fn room_key(&self) -> RoomKey {
+        RoomKey { room: self.announce.room.clone(), key: self.key.clone(), rejected: self.rejected.clone() }
+    }
+
+    /// 定时发送通告，等待发现新成员或者有成员连接进来
+    pub async fn next(&mut self) -> Event {
+        let mut buf = [0u8; 1024];
+        loop {
+            tokio::select! {
+                _ = self.ticker.tick() => {
+                    if let Err(e) = self.udp.send_to(&self.announce.package().unwrap(), self.target).await {
+                        debug!("发送通告到{}失败：{}", self.target, e);
+                    }
+                },
+                res = self.udp.recv_from(&mut buf) => {
+                    let (n, from) = match res {
+                        Ok(res) => res,
+                        Err(e) => {
+                            debug!("接收通告失败：{}", e);
+                            continue;
+                        },
+                    };
+                    match found(&self.announce, &buf[..n], from) {
+                        Some(ci) if !self.rejected.lock().unwrap().contains(&ci.id) => return Event::Found(ci),
+                        _ => {},
+                    }
+                },
+                res = self.listener.accept() => {
+                    match res {
+                        Ok((stm, addr)) => return Event::Accepted(stm, addr),
+                        Err(e) => warn!("接受连接失败：{}", e),
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// 解析收到的通告，返回需要由自己发起连接的成员
+/// 自己的通告、其他房间的通告，以及应该由对方发起连接的都不用管
+fn found(me: &LanAnnounce, buf: &[u8], from: SocketAddr) -> Option<ClientInfo> {
+    let other = serde_json::from_slice::<LanAnnounce>(buf).ok()?;
+    if other.room != me.room || other.user.id <= me.user.id {
+        return None;
+    }
+    let mut addr = other.addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(from.ip());
+    }
+    Some(ClientInfo { id: other.user.id, name: other.user.name, addr })
+}
+
+/// 多个客户端可以在同一台机器上监听通告端口，组播地址需要先加入组
+fn udp_socket(target: SocketAddr) -> Result<UdpSocket> {
+    let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_reuse_address(true)?;
+    #[cfg(target_family = "unix")]
+    {sock.set_reuse_port(true)?;}
+    sock.set_broadcast(true)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, target.port())).into())?;
+    let udp = UdpSocket::from_std(sock.into())?;
+    if let SocketAddr::V4(addr) = target {
+        if addr.ip().is_multicast() {
+            udp.join_multicast_v4(*addr.ip(), Ipv4Addr::UNSPECIFIED)?;
+        }
+    }
+    Ok(udp)
+}
+
+/// 用输入的用户名、房间名和房间密码开始在局域网中发现其他成员
+pub async fn start(name: String, room: String, key: Secret, target: SocketAddr) -> Result<(User, Lan)> {
+    let user = User {
+        // 没有服务端分配ID，随机生成一个
+        id: rand::random(),
+        name,
+        ..Default::default()
+    };
+    let lan = Lan::bind(&user, room, key, target).await?;
+    info!("局域网模式, ID: {}，在{}上监听，向{}发送通告", user.id, lan.announce.addr, target);
+    Ok((user, lan))
+}
+
+/// 连接发现的成员并交换信息，失败时返回对方的信息
+pub async fn connect(user_info: User, key: RoomKey, ci: ClientInfo)
+    -> std::result::Result<(ClientInfo, TcpStream), ClientInfo>
+{
+    let mut stm = match TcpStream::connect(ci.addr).await {
+        Ok(stm) => stm,
+        Err(e) => {
+            warn!("连接{:?}失败：{}", &ci, e);
+            return Err(ci);
+        },
+    };
+    match timeout(HANDSHAKE, handshake(&user_info, &key, &mut stm, ci.addr)).await {
+        Ok(Ok(other)) => {
+            info!("Connect: {:?}", &other);
+            Ok((other, stm))
+        },
+        Ok(Err(e)) => {
+            if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::PermissionDenied) {
+                warn!("连接{:?}失败：{}，不再连接", &ci, e);
+                key.rejected.lock().unwrap().insert(ci.id);
+            } else {
+                warn!("连接{:?}失败：{}", &ci, e);
+            }
+            Err(ci)
+        },
+        Err(_) => {
+            warn!("连接{:?}失败，无法验证身份", &ci);
+            Err(ci)
+        },
+    }
+}
+
+/// 和连接进来的成员交换信息，确认在同一个房间后才算作成员
+pub async fn accept(user_info: User, key: RoomKey, mut stm: TcpStream, addr: SocketAddr)
+    -> std::result::Result<(ClientInfo, TcpStream), ClientInfo>
+{
+    match timeout(HANDSHAKE, handshake(&user_info, &key, &mut stm, addr)).await {
+        Ok(Ok(other)) => {
+            info!("Connect: {:?}", &other);
+            Ok((other, stm))
+        },
+        Ok(Err(e)) => {
+            warn!("拒绝{}的连接：{}", addr, e);
+            Err(ClientInfo { id: 0, name: String::new(), addr })
+        },
+        Err(_) => {
+            warn!("{}连接进来后没有发送用户信息", addr);
+            Err(ClientInfo { id: 0, name: String::new(), addr })
+        },
+    }
+}
+
+/// 交换用户信息，再确认双方在同一个房间、房间密码相同
+async fn handshake(user_info: &User, key: &RoomKey, stm: &mut TcpStream, addr: SocketAddr) -> Result<ClientInfo> {
+    let other = swap_info(user_info, stm, addr).await?;
+    check_room(stm, key).await?;
+    Ok(other)
+}
+
+/// 双方先交换房间名和随机数，再用对方的随机数证明自己知道房间密码
+async fn check_room(stm: &mut TcpStream, key: &RoomKey) -> Result<()> {
+    let nonce: u64 = rand::random();
+    net::write(stm, &LanHello { room: key.room.clone(), nonce }.package()?).await?;
+    let hello: LanHello = read_json(stm).await?;
+    if hello.room != key.room {
+        return Err(Error::new(ErrorKind::InvalidData, format!("对方在房间{}中", hello.room)));
+    }
+    net::write(stm, &LanProof { proof: proof(key, hello.nonce) }.package()?).await?;
+    let other: LanProof = read_json(stm).await?;
+    if other.proof != proof(key, nonce) {
+        return Err(Error::new(ErrorKind::PermissionDenied, "房间密码不同"));
+    }
+    Ok(())
+}
+
+fn proof(key: &RoomKey, nonce: u64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key.key.expose().as_bytes());
+    hasher.update([0]);
+    hasher.update(key.room.as_bytes());
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(stm: &mut TcpStream) -> Result<T> {
+    match net::read(stm).await {
+        Ok(pkg) => Ok(serde_json::from_slice(&pkg)?),
+        Err(net::ErrorType::IO(e)) => Err(e),
+        Err(_) => Err(ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce(room: &str, id: ID, addr: &str) -> LanAnnounce {
+        LanAnnounce {
+            room: room.into(),
+            user: BaseUserInfo { id, name: format!("user{}", id) },
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn announce_codec() {
+        let me = announce("room1", 5, "0.0.0.0:4000");
+        let from: SocketAddr = "192.168.1.7:5567".parse().unwrap();
+        let found = |a: &LanAnnounce| super::found(&me, &a.package().unwrap(), from);
+        // 监听地址为0.0.0.0时用通告的来源IP
+        let ci = found(&announce("room1", 9, "0.0.0.0:4100")).unwrap();
+        assert_eq!((ci.id, ci.name.as_str(), ci.addr), (9, "user9", "192.168.1.7:4100".parse().unwrap()));
+        let ci = found(&announce("room1", 9, "10.0.0.2:4100")).unwrap();
+        assert_eq!(ci.addr, "10.0.0.2:4100".parse().unwrap());
+        // 自己的、ID比自己小的和其他房间的通告
+        assert!(found(&me).is_none());
+        assert!(found(&announce("room1", 3, "0.0.0.0:4100")).is_none());
+        assert!(found(&announce("room2", 9, "0.0.0.0:4100")).is_none());
+        assert!(super::found(&me, b"{\"room\":\"room1\"}", from).is_none());
+        assert!(super::found(&me, b"\xff", from).is_none());
+    }
+
+    async fn check(a: (&str, &str), b: (&str, &str)) -> (Result<()>, Result<()>) {
+        let key = |(room, key): (&str, &str)| RoomKey { room: room.into(), key: key.into(), rejected: Arc::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let (a, b) = (key(a), key(b));
+        tokio::join!(check_room(&mut client, &a), check_room(&mut server, &b))
+    }
+
+    #[tokio::test]
+    async fn room_check() {
+        let (a, b) = check(("room1", ""), ("room1", "")).await;
+        assert!(a.is_ok() && b.is_ok());
+        let (a, b) = check(("room1", "pw"), ("room1", "pw")).await;
+        assert!(a.is_ok() && b.is_ok());
+        let (a, b) = check(("room1", ""), ("room2", "")).await;
+        assert_eq!(a.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(b.unwrap_err().kind(), ErrorKind::InvalidData);
+        let (a, b) = check(("room1", "pw"), ("room1", "other")).await;
+        assert_eq!(a.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(b.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let (a, _) = check(("room1", "pw"), ("room1", "")).await;
+        assert!(a.is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! 聊天客户端的核心逻辑：登录、加入房间、与其他成员建立连接和收发消息。
+//!
+//! 通过[`ChatSession`]的方法发出操作，通过返回的[`Event`]接收消息和状态变化，
+//! 命令行客户端、机器人和测试都建立在它上面。
+
+use std::{collections::HashMap, time::Duration};
+use net::ID;
+use receipts::Receipts;
+use rooms::Rooms;
+
+mod cmd;
+mod history;
+mod lan;
+mod peer;
+mod receipts;
+mod redial;
+mod rooms;
+mod session;
+mod transfer;
+
+pub use cmd::Cmd;
+pub use lan::DEFAULT_LAN_ADDR;
+pub use session::{ChatSession, ConnState, Event};
+
+// 本地保存的聊天记录条数，也是加入房间时向其他客户端请求的条数
+const HISTORY_LIMIT: usize = 100;
+// 发出消息后等待送达回执的时间
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+// 向服务端发送心跳包的间隔，服务端长时间收不到会断开连接
+const HEARTBEAT: Duration = Duration::from_secs(5);
+// 与服务端断线后重连的间隔，每次失败翻倍
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+// 检查与peer的连接是否断开的间隔
+const PEER_CHECK: Duration = Duration::from_secs(1);
+// 一次重连最多等待的时间
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// 聊天消息最多被转发的次数
+const RELAY_TTL: u8 = 8;
+
+/// 已加入的房间和消息回执，在处理服务端的task和所有peer之间共享
+struct ChatState {
+    rooms: Rooms,
+    receipts: Receipts,
+    // 已连接的成员，值为连接数，用来决定转发消息给谁
+    connected: HashMap<ID, usize>,
+}
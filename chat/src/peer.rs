@@ -0,0 +1,521 @@
+//! 与其他成员之间的连接，每个连接由一个task处理
+
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use log::{debug, error, info, warn};
+use net::{self, BaseUserInfo, ToPackage, User, ClientInfo, ChatMsg, FileOffer, PeerPkg, ID};
+use net::mux::{ChannelId, MuxSender};
+use tokio::{
+    io::Result,
+    fs::File,
+    net::{TcpSocket, TcpStream},
+    sync::{broadcast, mpsc::{self, Sender, Receiver}, Mutex},
+    task::JoinSet,
+    time::{sleep_until, timeout, Instant}
+};
+use crate::{ChatState, Event, HISTORY_LIMIT};
+use crate::rooms::Received;
+use crate::transfer::{self, ChunkResult, Failed, Transfers, human_size};
+
+/// 从本地地址连接其他成员并交换信息，失败时返回对方的信息
+pub async fn connect_peer(addr: SocketAddr, user_info: User, ci: ClientInfo)
+    -> std::result::Result<(ClientInfo, TcpStream), ClientInfo>
+{
+    let mut stm = {
+        let sock = TcpSocket::new_v4().unwrap();
+        #[cfg(target_family = "unix")]
+        {sock.set_reuseport(true).unwrap();}
+        sock.set_reuseaddr(true).unwrap();
+        if let Err(e) = sock.bind(addr) {
+            error!("Fail to bind {} {}", &addr, e);
+            return Err(ci);
+        }
+        match sock.connect(ci.addr).await {
+            Ok(stm) => stm,
+            Err(e) => {
+                debug!("连接{:?}失败：{}", &ci, e);
+                return Err(ci);
+            },
+        }
+    };
+    match swap_info(&user_info, &mut stm, ci.addr).await {
+        Ok(other) => {
+            info!("Connect: {:?}", &other);
+            Ok((other, stm))
+        },
+        Err(_) => {
+            warn!("连接{:?}失败，无法验证身份", &ci);
+            Err(ci)
+        },
+    }
+}
+
+/// 按服务端安排的时间表连接对方，对方也在同一时间连接自己，失败时返回对方的信息
+pub async fn punch(addr: SocketAddr, user_info: User, ci: ClientInfo, plan: (Duration, Duration, u32))
+    -> std::result::Result<(ClientInfo, TcpStream), ClientInfo>
+{
+    let (delay, interval, attempts) = plan;
+    let start = Instant::now() + delay;
+    for i in 0..attempts.max(1) {
+        sleep_until(start + interval * i).await;
+        // 每次最多等到下一次连接的时间
+        match timeout(interval.max(Duration::from_millis(100)), connect_peer(addr, user_info.clone(), ci.clone())).await {
+            Ok(Ok(res)) => return Ok(res),
+            _ => debug!("第{}次连接{}失败", i + 1, ci.name),
+        }
+    }
+    warn!("连接{:?}失败，已尝试{}次", &ci, attempts.max(1));
+    Err(ci)
+}
+
+/// 交换相互的信息
+pub async fn swap_info(user_info: &User, sock: &mut TcpStream, addr: SocketAddr) -> Result<ClientInfo> {
+    // 将自己的信息发送到连接的客户端
+    let bui = net::BaseUserInfo {
+        id: user_info.id,
+        name: user_info.name.clone(),
+    };
+    net::write(sock, &serde_json::to_vec(&bui).unwrap()).await?;
+    // 接收传过来的信息
+    let other = {
+        let bui = serde_json::from_slice::<net::BaseUserInfo>(
+                &match net::read(sock).await {
+                    Ok(pkg) => { pkg },
+                    Err(e) => { return match e {
+                        net::ErrorType::IO(e) => { Err(e) }
+                        _ => { Err(std::io::ErrorKind::Other.into()) },
+                    }; },
+                })?;
+        ClientInfo {
+            id: bui.id,
+            name: bui.name,
+            addr,
+        }
+    };
+    // 这里就可以对传过来的信息和服务端的信息进行比对
+    // TODO
+    Ok(other)
+}
+
+pub struct PeerInfo {
+    pub ci: ClientInfo,
+    pub handle: tokio::task::JoinHandle<()>,
+    pub tx: Sender<PeerCmd>,
+}
+
+/// 在ChatState中记录与某个成员的连接，task结束或者被abort时移除，同时通知连接状态的变化
+struct Online {
+    chat: Arc<Mutex<ChatState>>,
+    user: BaseUserInfo,
+    ev_tx: Sender<Event>,
+}
+
+impl Online {
+    async fn new(chat: &Arc<Mutex<ChatState>>, user: BaseUserInfo, ev_tx: &Sender<Event>) -> Self {
+        *chat.lock().await.connected.entry(user.id).or_default() += 1;
+        ev_tx.send(Event::PeerConnected(user.clone())).await.ok();
+        Self { chat: chat.clone(), user, ev_tx: ev_tx.clone() }
+    }
+}
+
+impl Drop for Online {
+    fn drop(&mut self) {
+        let (chat, user, ev_tx) = (self.chat.clone(), self.user.clone(), self.ev_tx.clone());
+        tokio::spawn(async move {
+            {
+                let mut chat = chat.lock().await;
+                if let Some(n) = chat.connected.get_mut(&user.id) {
+                    *n -= 1;
+                    if *n == 0 {
+                        chat.connected.remove(&user.id);
+                    }
+                }
+            }
+            ev_tx.send(Event::PeerDisconnected(user)).await.ok();
+        });
+    }
+}
+
+/// 发给单个peer的指令
+#[derive(Debug)]
+pub enum PeerCmd {
+    SendFile(PathBuf),
+    /// 私信，只发给这一个peer
+    Direct(ChatMsg),
+    /// 对方也加入了这个房间，同步双方的聊天记录
+    Share(ID),
+    Accept(String),
+    Decline(String),
+}
+
+/// 创建一个task处理与peer之间的连接
+pub fn spawn_peer(ci: &ClientInfo, sock: TcpStream, ev_tx: Sender<Event>,
+    out_tx: broadcast::Sender<PeerPkg>, chat: Arc<Mutex<ChatState>>
+) -> PeerInfo {
+    let (tx, rx) = mpsc::channel::<PeerCmd>(16);
+    let handle = tokio::spawn(Peer::new(ci, sock, ev_tx, out_tx, rx, chat).poll());
+    PeerInfo { ci: ci.clone(), handle, tx }
+}
+
+/// 计算哈希等很慢的文件操作在单独的task中进行，完成后交给peer的task继续处理
+enum Job {
+    /// 要发送的文件已经打开并算好了哈希
+    Prepared(PathBuf, io::Result<(File, FileOffer)>),
+    /// 接收完的文件校验和保存的结果
+    Verified(FileOffer, io::Result<(bool, PathBuf)>),
+}
+
+struct Peer {
+    ci: ClientInfo,
+    mux: MuxSender,
+    mux_rx: Receiver<(ChannelId, Vec<u8>)>,
+    ev_tx: Sender<Event>,
+    // 转发聊天消息给其他peer
+    out_tx: broadcast::Sender<PeerPkg>,
+    out_rx: broadcast::Receiver<PeerPkg>,
+    cmd_rx: Receiver<PeerCmd>,
+    chat: Arc<Mutex<ChatState>>,
+    transfers: Transfers,
+    jobs: JoinSet<Job>,
+}
+
+impl Peer {
+    fn new(ci: &ClientInfo, sock: TcpStream, ev_tx: Sender<Event>,
+        out_tx: broadcast::Sender<PeerPkg>, cmd_rx: Receiver<PeerCmd>, chat: Arc<Mutex<ChatState>>
+    ) -> Self {
+        // 聊天、历史记录和文件传输共用一条连接
+        let (mux, mux_rx) = net::mux::split(sock);
+        Self {
+            ci: ClientInfo {
+                id: ci.id,
+                name: ci.name.clone(),
+                addr: ci.addr,
+            }, mux, mux_rx, ev_tx, out_rx: out_tx.subscribe(), out_tx, cmd_rx, chat,
+            transfers: Transfers::default(),
+            jobs: JoinSet::new(),
+        }
+    }
+
+    async fn send(&self, pkg: PeerPkg) -> Result<()> {
+        self.mux.send(pkg.channel(), pkg.package().unwrap()).await
+    }
+
+    /// 同一条消息只通知一次，不是当前房间的消息切换过去时会作为聊天记录再给出，返回是否第一次收到
+    async fn receive(&self, msg: ChatMsg) -> bool {
+        let received = self.chat.lock().await.rooms.receive(self.ci.id, msg.clone());
+        let current = match received {
+            Received::Show => true,
+            Received::Unseen { name, count } => {
+                if count == 1 {
+                    info!("房间{}有新消息，输入 :room {} 查看", name, name);
+                }
+                false
+            },
+            Received::Ignored => return false,
+        };
+        self.ev_tx.send(Event::Message { msg, current }).await.ok();
+        true
+    }
+
+    async fn poll(mut self) {
+        let bui = BaseUserInfo{ id: self.ci.id, name: self.ci.name.clone() };
+        let _online = Online::new(&self.chat, bui.clone(), &self.ev_tx).await;
+        // 连接建立后先向对方请求共同所在的房间最近的聊天记录
+        let rooms = self.chat.lock().await.rooms.shared(self.ci.id);
+        for room in rooms {
+            if self.send(PeerPkg::HistoryReq { room, limit: HISTORY_LIMIT }).await.is_err() {
+                info!("Disconnect: {:?}", bui);
+                return;
+            }
+        }
+        loop {
+            tokio::select! {
+                frame = self.mux_rx.recv() => {
+                    let (ch, pkg) = if let Some(frame) = frame { frame } else { break; };
+                    match PeerPkg::from(&pkg) {
+                        Ok(pkg) => {
+                            if self.parse_pakage(pkg).await.is_err() {
+                                break;
+                            }
+                        },
+                        Err(e) => {
+                            warn!("Unknown Pakage from {:?} on channel {}: {}", bui, ch, e);
+                        },
+                    }
+                },
+                pkg = self.out_rx.recv() => {
+                    let pkg = match pkg {
+                        Ok(pkg) => pkg,
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            warn!("{}条消息未能发送给{:?}", n, bui);
+                            continue;
+                        },
+                        Err(broadcast::error::RecvError::Closed) => {
+                            break;
+                        },
+                    };
+                    // 聊天消息只发给同一个房间的成员，转发的消息只发给选中的peer
+                    let skip = match &pkg {
+                        PeerPkg::Chat(msg) => !self.chat.lock().await.rooms.is_member(msg.room, self.ci.id),
+                        PeerPkg::Relay { to, .. } => !to.contains(&self.ci.id),
+                        _ => false,
+                    };
+                    if skip {
+                        continue;
+                    }
+                    if self.send(pkg).await.is_err() {
+                        break;
+                    }
+                },
+                cmd = self.cmd_rx.recv() => {
+                    let cmd = if let Some(cmd) = cmd { cmd } else { break; };
+                    if self.handle_cmd(cmd).await.is_err() {
+                        break;
+                    }
+                },
+                // 文件通道有空位时才读取下一个文件块，不会阻塞其他消息的处理
+                permit = self.mux.reserve(net::mux::channel::FILE), if self.transfers.sending() => {
+                    let permit = if let Ok(permit) = permit { permit } else { break; };
+                    let (pkg, progress) = match self.transfers.next_chunk().await {
+                        Ok(Some(chunk)) => chunk,
+                        Ok(None) => continue,
+                        Err(failed) => {
+                            drop(permit);
+                            if self.abort(failed, "读取").await.is_err() {
+                                break;
+                            }
+                            continue;
+                        },
+                    };
+                    if permit.send(pkg.package().unwrap()).is_err() {
+                        break;
+                    }
+                    if let Some((name, percent)) = progress {
+                        info!("发送{}给{}：{}%", name, self.ci.name, percent);
+                    }
+                },
+                Some(job) = self.jobs.join_next(), if !self.jobs.is_empty() => {
+                    let job = match job {
+                        Ok(job) => job,
+                        Err(e) => {
+                            error!("处理文件的task失败：{}", e);
+                            continue;
+                        },
+                    };
+                    if self.finish_job(job).await.is_err() {
+                        break;
+                    }
+                },
+            };
+        }
+        info!("Disconnect: {:?}", bui);
+    }
+
+    // 处理其他客户端发送过来的数据包
+    async fn parse_pakage(&mut self, pkg: PeerPkg) -> Result<()> {
+        match pkg {
+            PeerPkg::Chat(msg) => {
+                // 直接发来的消息只能是对方自己的，别人的消息只能通过Relay转发
+                if msg.sender.id != self.ci.id || msg.sender.name != self.ci.name {
+                    warn!("{}发来的消息#{}冒用了{}的身份，已丢弃", self.ci.name, msg.tag(), msg.sender.name);
+                    return Ok(());
+                }
+                let id = msg.id;
+                self.receive(msg).await;
+                // 重复的消息也要回复，对方可能没有收到上一次的回执
+                self.send(PeerPkg::Ack(id)).await?;
+            },
+            PeerPkg::Relay { msg, ttl, mut seen, .. } => {
+                // 只接受房间内其他成员的消息，members中没有自己，冒用自己身份的消息也会被丢弃
+                if !self.chat.lock().await.rooms.is_member(msg.room, msg.sender.id) {
+                    warn!("{}转发的消息#{}的发送者{}不是房间成员，已丢弃", self.ci.name, msg.tag(), msg.sender.name);
+                    return Ok(());
+                }
+                let id = msg.id;
+                if self.receive(msg.clone()).await && ttl > 0 {
+                    // 第一次收到时转发给还没有收到过的已连接成员
+                    let to: Vec<ID> = {
+                        let chat = self.chat.lock().await;
+                        chat.rooms.get(msg.room).map_or(Vec::new(), |v| {
+                            v.members.iter()
+                                .filter(|id| chat.connected.contains_key(id) && !seen.contains(id))
+                                .copied()
+                                .collect()
+                        })
+                    };
+                    if !to.is_empty() {
+                        debug!("转发{}的消息#{}给{:?}", msg.sender.name, msg.tag(), to);
+                        seen.extend(&to);
+                        self.out_tx.send(PeerPkg::Relay { msg, ttl: ttl - 1, seen, to }).ok();
+                    }
+                }
+                self.send(PeerPkg::Ack(id)).await?;
+            },
+            PeerPkg::Direct(mut msg) => {
+                // 私聊只能是对方自己发的，发送者以连接的对方为准
+                msg.sender = BaseUserInfo { id: self.ci.id, name: self.ci.name.clone() };
+                self.ev_tx.send(Event::Direct(msg)).await.ok();
+            },
+            PeerPkg::HistoryReq { room, limit } => {
+                let msgs = self.chat.lock().await.rooms.recent(room, self.ci.id, limit.min(HISTORY_LIMIT));
+                debug!("发送{}条聊天记录给{:?}", msgs.len(), self.ci);
+                self.send(PeerPkg::History(msgs)).await?;
+            },
+            PeerPkg::History(msgs) => {
+                let (new_msgs, others) = self.chat.lock().await.rooms.merge(self.ci.id, msgs);
+                if !new_msgs.is_empty() {
+                    info!("从{}同步了{}条聊天记录", self.ci.name, new_msgs.len());
+                    self.ev_tx.send(Event::History(new_msgs)).await.ok();
+                }
+                if others > 0 {
+                    info!("从{}同步了其他房间的{}条聊天记录", self.ci.name, others);
+                }
+            },
+            PeerPkg::Ack(id) => {
+                if self.chat.lock().await.receipts.delivered(id, self.ci.id) {
+                    info!("消息#{} 已全部送达", net::id_tag(id));
+                }
+            },
+            PeerPkg::Read(ids) => {
+                let mut chat = self.chat.lock().await;
+                for id in ids {
+                    if chat.receipts.read(id, self.ci.id) {
+                        info!("消息#{} 所有人都已读", net::id_tag(id));
+                    }
+                }
+            },
+            PeerPkg::FileOffer(offer) => {
+                let (id, tag) = (offer.id, net::id_tag(offer.id));
+                let inc = match self.transfers.offered(offer) {
+                    Some(inc) => inc,
+                    None => {
+                        warn!("{}同时发送的文件太多，已自动拒绝#{}", self.ci.name, tag);
+                        return self.send(PeerPkg::FileDecline(id)).await;
+                    },
+                };
+                let partial = inc.partial().await;
+                let resume = if partial > 0 {
+                    format!("，已下载{}，接收后将继续传输", human_size(partial))
+                } else {
+                    String::new()
+                };
+                info!("{}想发送文件{}（{}）{}，输入 :accept {} 接收或 :decline {} 拒绝",
+                        self.ci.name, inc.offer.name, human_size(inc.offer.size), resume, tag, tag);
+            },
+            PeerPkg::FileAccept { id, offset } => {
+                match self.transfers.accepted(id, offset).await {
+                    Ok(Some(offer)) => info!("{}开始接收{}", self.ci.name, offer.name),
+                    Ok(None) => {},
+                    Err(failed) => self.abort(failed, "读取").await?,
+                }
+            },
+            PeerPkg::FileDecline(id) => {
+                if let Some(out) = self.transfers.finished(id) {
+                    warn!("{}拒绝接收{}", self.ci.name, out.offer.name);
+                }
+            },
+            PeerPkg::FileChunk { id, offset, data } => {
+                let name = self.transfers.incoming_name(id).unwrap_or_default();
+                match self.transfers.chunk(id, offset, &data).await {
+                    Ok(ChunkResult::Ignored) => {},
+                    Ok(ChunkResult::Progress(percent)) => {
+                        info!("接收{}：{}%", name, percent);
+                    },
+                    Ok(ChunkResult::Complete(inc)) => {
+                        info!("{}接收完毕，正在校验", name);
+                        self.jobs.spawn(async move {
+                            let offer = inc.offer.clone();
+                            Job::Verified(offer, inc.verify().await)
+                        });
+                    },
+                    // 保留已经接收的部分，下次可以继续
+                    Err(failed) => self.abort(failed, "保存").await?,
+                }
+            },
+            PeerPkg::FileAbort { id, reason } => {
+                if let Some(name) = self.transfers.abort(id) {
+                    error!("{}取消了{}的传输：{}", self.ci.name, name, reason);
+                }
+            },
+            PeerPkg::FileResult { id, ok } => {
+                if let Some(out) = self.transfers.finished(id) {
+                    if ok {
+                        info!("{}已收到{}", self.ci.name, out.offer.name);
+                    } else {
+                        error!("{}接收{}时校验失败", self.ci.name, out.offer.name);
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// 读写文件失败，只取消这一个文件的传输，并告诉对方
+    async fn abort(&mut self, failed: Failed, action: &str) -> Result<()> {
+        let (offer, e) = failed;
+        error!("{}{}失败，已取消传输：{}", action, offer.name, e);
+        self.send(PeerPkg::FileAbort { id: offer.id, reason: format!("{}文件失败", action) }).await
+    }
+
+    async fn finish_job(&mut self, job: Job) -> Result<()> {
+        match job {
+            Job::Prepared(_, Ok((file, offer))) => {
+                info!("等待{}接收{}（{}）", self.ci.name, offer.name, human_size(offer.size));
+                self.transfers.add(file, offer.clone());
+                self.send(PeerPkg::FileOffer(offer)).await?;
+            },
+            Job::Prepared(path, Err(e)) => {
+                warn!("无法读取{}：{}", path.display(), e);
+            },
+            Job::Verified(offer, Ok((ok, path))) => {
+                if ok {
+                    info!("文件已保存到{}", path.display());
+                } else {
+                    error!("{}校验失败，已删除", offer.name);
+                }
+                self.send(PeerPkg::FileResult { id: offer.id, ok }).await?;
+            },
+            Job::Verified(offer, Err(e)) => self.abort((offer, e), "保存").await?,
+        }
+        Ok(())
+    }
+
+    // 处理用户对这个peer的指令
+    async fn handle_cmd(&mut self, cmd: PeerCmd) -> Result<()> {
+        match cmd {
+            PeerCmd::Share(room) => {
+                // 对方可能还不知道自己在这个房间，直接把记录发过去
+                let msgs = self.chat.lock().await.rooms.recent(room, self.ci.id, HISTORY_LIMIT);
+                if !msgs.is_empty() {
+                    self.send(PeerPkg::History(msgs)).await?;
+                }
+                self.send(PeerPkg::HistoryReq { room, limit: HISTORY_LIMIT }).await?;
+            },
+            PeerCmd::Direct(msg) => {
+                self.send(PeerPkg::Direct(msg)).await?;
+            },
+            PeerCmd::SendFile(path) => {
+                info!("正在计算{}的校验值", path.display());
+                self.jobs.spawn(async move {
+                    let res = transfer::prepare(path.clone()).await;
+                    Job::Prepared(path, res)
+                });
+            },
+            PeerCmd::Accept(tag) => {
+                match self.transfers.accept(&tag).await {
+                    Ok(Some((id, offset))) => {
+                        self.send(PeerPkg::FileAccept { id, offset }).await?;
+                    },
+                    Ok(None) => {},
+                    Err(e) => error!("无法创建文件：{}", e),
+                }
+            },
+            PeerCmd::Decline(tag) => {
+                if let Some(id) = self.transfers.decline(&tag) {
+                    self.send(PeerPkg::FileDecline(id)).await?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
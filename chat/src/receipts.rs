@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use net::ID;
+
+/// 自己发出的一条消息的送达情况
+#[derive(Debug)]
+pub struct Receipt {
+    /// 发送时房间内连接着的peer数量
+    pub total: usize,
+    pub delivered: HashSet<ID>,
+    pub read: HashSet<ID>,
+}
+
+impl Receipt {
+    pub fn status(&self) -> String {
+        if self.total == 0 {
+            "已发送".into()
+        } else if self.read.len() >= self.total {
+            format!("已读 {}/{}", self.read.len(), self.total)
+        } else {
+            format!("已送达 {}/{}，已读 {}/{}",
+                    self.delivered.len(), self.total, self.read.len(), self.total)
+        }
+    }
+}
+
+/// 记录最近发出的消息的回执
+#[derive(Debug, Default)]
+pub struct Receipts {
+    by_id: HashMap<u64, Receipt>,
+    order: VecDeque<u64>,
+    cap: usize,
+}
+
+impl Receipts {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            by_id: HashMap::new(),
+            order: VecDeque::new(),
+            cap,
+        }
+    }
+
+    pub fn sent(&mut self, id: u64, total: usize) {
+        self.by_id.insert(id, Receipt {
+            total,
+            delivered: HashSet::new(),
+            read: HashSet::new(),
+        });
+        self.order.push_back(id);
+        if self.order.len() > self.cap {
+            let old = self.order.pop_front().unwrap();
+            self.by_id.remove(&old);
+        }
+    }
+
+    /// 收到送达回执，所有人都收到时返回true
+    pub fn delivered(&mut self, id: u64, from: ID) -> bool {
+        if let Some(r) = self.by_id.get_mut(&id) {
+            if r.delivered.insert(from) {
+                return r.delivered.len() == r.total;
+            }
+        }
+        false
+    }
+
+    /// 收到已读回执，所有人都已读时返回true
+    pub fn read(&mut self, id: u64, from: ID) -> bool {
+        if let Some(r) = self.by_id.get_mut(&id) {
+            // 已读一定已送达
+            r.delivered.insert(from);
+            if r.read.insert(from) {
+                return r.read.len() == r.total;
+            }
+        }
+        false
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Receipt> {
+        self.by_id.get(&id)
+    }
+
+    /// 还没有送达所有人的消息数
+    pub fn pending(&self) -> usize {
+        self.by_id.values().filter(|r| r.delivered.len() < r.total).count()
+    }
+
+    /// 最近发出的limit条消息的ID
+    pub fn recent(&self, limit: usize) -> Vec<u64> {
+        let start = self.order.len().saturating_sub(limit);
+        self.order.iter().skip(start).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delivered_and_read() {
+        let mut r = Receipts::new(10);
+        r.sent(1, 2);
+        assert!(!r.delivered(1, 10));
+        // 重复的回执不重复计数
+        assert!(!r.delivered(1, 10));
+        assert_eq!(r.pending(), 1);
+        assert!(r.delivered(1, 11));
+        assert_eq!(r.pending(), 0);
+        assert_eq!(r.get(1).unwrap().status(), "已送达 2/2，已读 0/2");
+        assert!(!r.read(1, 10));
+        assert!(r.read(1, 11));
+        assert_eq!(r.get(1).unwrap().status(), "已读 2/2");
+    }
+
+    #[test]
+    fn read_implies_delivered() {
+        let mut r = Receipts::new(10);
+        r.sent(1, 2);
+        r.read(1, 10);
+        let receipt = r.get(1).unwrap();
+        assert!(receipt.delivered.contains(&10));
+        assert_eq!(receipt.status(), "已送达 1/2，已读 1/2");
+    }
+
+    #[test]
+    fn unknown_and_evicted() {
+        let mut r = Receipts::new(2);
+        assert!(!r.delivered(1, 10));
+        r.sent(1, 1);
+        r.sent(2, 1);
+        r.sent(3, 0);
+        assert!(r.get(1).is_none());
+        assert_eq!(r.recent(10), [2, 3]);
+        assert_eq!(r.recent(1), [3]);
+        assert_eq!(r.get(3).unwrap().status(), "已发送");
+    }
+
+    #[test]
+    fn pending_counts_only_undelivered() {
+        let mut r = Receipts::new(10);
+        // 发送时没有连接的成员，不等待回执
+        r.sent(1, 0);
+        r.sent(2, 1);
+        r.sent(3, 2);
+        assert_eq!(r.pending(), 2);
+        r.read(2, 10);
+        assert_eq!(r.pending(), 1);
+        assert!(!r.delivered(3, 10));
+        assert_eq!(r.get(3).unwrap().status(), "已送达 1/2，已读 0/2");
+        assert!(r.delivered(3, 11));
+        assert_eq!(r.pending(), 0);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::{HashMap, HashSet};
+use net::{ClientInfo, ID};
+use tokio::time::{Duration, Instant};
+use crate::{RECONNECT_MAX, RECONNECT_MIN};
+
+struct Dropped {
+    ci: ClientInfo,
+    // 下一次请求重新连接的时间和间隔，每次失败翻倍
+    next: Instant,
+    backoff: Duration,
+}
+
+/// 连接意外断开、但仍然在同一个房间中的成员，定时请求服务端协调双方重新连接
+#[derive(Default)]
+pub struct Redial {
+    dropped: HashMap<ID, Dropped>,
+    // 正在重新连接的成员，期间不再请求
+    dialing: HashSet<ID>,
+}
+
+impl Redial {
+    /// 记录断开的连接，第一次请求在RECONNECT_MIN之后
+    pub fn dropped(&mut self, ci: ClientInfo) {
+        let now = Instant::now();
+        self.dropped.entry(ci.id).or_insert(Dropped { ci, next: now + RECONNECT_MIN, backoff: RECONNECT_MIN });
+    }
+
+    /// 已经重新连接上，或者不再需要连接
+    pub fn remove(&mut self, id: ID) -> bool {
+        self.dialing.remove(&id);
+        self.dropped.remove(&id).is_some()
+    }
+
+    /// 开始连接，已经在连接时返回false
+    pub fn dial(&mut self, id: ID) -> bool {
+        self.dialing.insert(id)
+    }
+
+    /// 这一次连接结束，失败时等到下一次到期再请求
+    pub fn dialed(&mut self, id: ID) {
+        self.dialing.remove(&id);
+    }
+
+    /// 到期需要请求服务端的成员，同时把间隔翻倍
+    pub fn due(&mut self, now: Instant) -> Vec<ClientInfo> {
+        let mut due = Vec::new();
+        for d in self.dropped.values_mut() {
+            if d.next > now || self.dialing.contains(&d.ci.id) {
+                continue;
+            }
+            d.backoff = (d.backoff * 2).min(RECONNECT_MAX);
+            d.next = now + d.backoff;
+            due.push(d.ci.clone());
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ci(id: ID) -> ClientInfo {
+        ClientInfo { id, name: format!("user{}", id), addr: ([127, 0, 0, 1], 5000).into() }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut r = Redial::default();
+        r.dropped(ci(1));
+        let start = Instant::now();
+        assert!(r.due(start).is_empty());
+        let mut now = start + RECONNECT_MIN;
+        let mut backoff = RECONNECT_MIN;
+        for _ in 0..10 {
+            assert_eq!(r.due(now).len(), 1);
+            // 还没到下一次
+            assert!(r.due(now).is_empty());
+            backoff = (backoff * 2).min(RECONNECT_MAX);
+            now += backoff;
+        }
+        assert_eq!(backoff, RECONNECT_MAX);
+    }
+
+    #[test]
+    fn dialing_is_skipped() {
+        let mut r = Redial::default();
+        r.dropped(ci(1));
+        let later = Instant::now() + RECONNECT_MAX;
+        assert!(r.dial(1));
+        assert!(!r.dial(1));
+        assert!(r.due(later).is_empty());
+        r.dialed(1);
+        assert_eq!(r.due(later)[0].id, 1);
+        assert!(r.remove(1));
+        assert!(!r.remove(1));
+        assert!(r.due(later + RECONNECT_MAX).is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, HashSet};
+use net::{ChatMsg, Room, ID};
+use super::history::History;
+
+/// 一个已加入的房间，包括房间内的其他成员和聊天记录
+pub struct RoomView {
+    pub room: Room,
+    /// 房间内其他成员的ID
+    pub members: HashSet<ID>,
+    pub history: History,
+    /// 不是当前房间时收到的新消息数，切换过来时再显示
+    pub unseen: usize,
+}
+
+/// 收到一条聊天消息后的结果
+pub enum Received {
+    /// 不在这个房间，或者已经收到过
+    Ignored,
+    /// 当前房间的消息，直接显示
+    Show,
+    /// 其他房间的消息，count为这个房间还没看的消息数
+    Unseen { name: String, count: usize },
+}
+
+/// 同时加入的所有房间，输入的消息发往当前房间
+#[derive(Default)]
+pub struct Rooms {
+    views: HashMap<ID, RoomView>,
+    current: Option<ID>,
+    // 每个房间保存的聊天记录条数
+    cap: usize,
+}
+
+impl Rooms {
+    pub fn new(cap: usize) -> Self {
+        Self { cap, ..Default::default() }
+    }
+
+    /// 加入房间并切换过去
+    pub fn join(&mut self, room: Room, members: impl IntoIterator<Item = ID>) {
+        let id = room.id;
+        self.views.insert(id, RoomView {
+            room,
+            members: members.into_iter().collect(),
+            history: History::new(self.cap),
+            unseen: 0,
+        });
+        self.current = Some(id);
+    }
+
+    /// 离开房间，离开的是当前房间时切换到ID最小的房间
+    pub fn leave(&mut self, id: ID) -> Option<RoomView> {
+        let view = self.views.remove(&id)?;
+        if self.current == Some(id) {
+            self.current = self.views.keys().min().copied();
+        }
+        Some(view)
+    }
+
+    pub fn get(&self, id: ID) -> Option<&RoomView> {
+        self.views.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: ID) -> Option<&mut RoomView> {
+        self.views.get_mut(&id)
+    }
+
+    pub fn current(&self) -> Option<&RoomView> {
+        self.views.get(&self.current?)
+    }
+
+    /// 按房间名或ID查找已加入的房间
+    pub fn find(&self, key: &str) -> Option<ID> {
+        self.views.values()
+            .find(|v| v.room.name == key)
+            .or_else(|| key.parse().ok().and_then(|id| self.views.get(&id)))
+            .map(|v| v.room.id)
+    }
+
+    /// 切换当前房间，返回切换前没看的消息
+    pub fn switch(&mut self, id: ID) -> Vec<ChatMsg> {
+        let view = match self.views.get_mut(&id) {
+            Some(view) => view,
+            None => return Vec::new(),
+        };
+        self.current = Some(id);
+        let unseen = std::mem::take(&mut view.unseen);
+        view.history.recent(unseen)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.views.is_empty()
+    }
+
+    /// 按ID排序的所有房间
+    pub fn list(&self) -> Vec<&RoomView> {
+        let mut views: Vec<&RoomView> = self.views.values().collect();
+        views.sort_by_key(|v| v.room.id);
+        views
+    }
+
+    pub fn is_current(&self, id: ID) -> bool {
+        self.current == Some(id)
+    }
+
+    pub fn is_member(&self, room: ID, peer: ID) -> bool {
+        self.views.get(&room).is_some_and(|v| v.members.contains(&peer))
+    }
+
+    /// 和这个成员共同所在的房间
+    pub fn shared(&self, peer: ID) -> Vec<ID> {
+        self.views.values()
+            .filter(|v| v.members.contains(&peer))
+            .map(|v| v.room.id)
+            .collect()
+    }
+
+    /// 成员离开房间，返回是否还有共同所在的房间
+    pub fn peer_left(&mut self, room: ID, peer: ID) -> bool {
+        if let Some(view) = self.views.get_mut(&room) {
+            view.members.remove(&peer);
+        }
+        self.views.values().any(|v| v.members.contains(&peer))
+    }
+
+    /// 收到from发来的消息，只接受双方共同所在的房间的消息
+    pub fn receive(&mut self, from: ID, msg: ChatMsg) -> Received {
+        let current = self.current == Some(msg.room);
+        let view = match self.views.get_mut(&msg.room) {
+            Some(view) if view.members.contains(&from) => view,
+            _ => return Received::Ignored,
+        };
+        if !view.history.insert(msg) {
+            Received::Ignored
+        } else if current {
+            Received::Show
+        } else {
+            view.unseen += 1;
+            Received::Unseen { name: view.room.name.clone(), count: view.unseen }
+        }
+    }
+
+    /// 合并from同步过来的记录，返回当前房间的新消息和其他房间的新消息数
+    pub fn merge(&mut self, from: ID, msgs: Vec<ChatMsg>) -> (Vec<ChatMsg>, usize) {
+        let mut by_room: HashMap<ID, Vec<ChatMsg>> = HashMap::new();
+        for msg in msgs {
+            by_room.entry(msg.room).or_default().push(msg);
+        }
+        let mut shown = Vec::new();
+        let mut others = 0;
+        for (id, msgs) in by_room {
+            let current = self.current == Some(id);
+            let view = match self.views.get_mut(&id) {
+                Some(view) if view.members.contains(&from) => view,
+                _ => continue,
+            };
+            let new_msgs = view.history.merge(msgs);
+            if current {
+                shown = new_msgs;
+            } else {
+                view.unseen += new_msgs.len();
+                others += new_msgs.len();
+            }
+        }
+        (shown, others)
+    }
+
+    /// 发给peer的某个房间最近的聊天记录，对方不在这个房间时为空
+    pub fn recent(&self, room: ID, peer: ID, limit: usize) -> Vec<ChatMsg> {
+        match self.views.get(&room) {
+            Some(view) if view.members.contains(&peer) => view.history.recent(limit),
+            _ => Vec::new(),
+        }
+    }
+
+    /// 在所有房间中查找消息
+    pub fn msg(&self, id: u64) -> Option<&ChatMsg> {
+        self.views.values().find_map(|v| v.history.get(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use net::BaseUserInfo;
+    use super::*;
+
+    fn room(id: ID, name: &str) -> Room {
+        Room { id, name: name.into(), ..Default::default() }
+    }
+
+    fn msg(id: u64, room: ID, sender: ID) -> ChatMsg {
+        ChatMsg {
+            id,
+            sender: BaseUserInfo { id: sender, name: format!("user{}", sender) },
+            time: id as i64,
+            room,
+            body: String::new(),
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn join_switch_leave() {
+        let mut rooms = Rooms::new(10);
+        rooms.join(room(1, "a"), [10]);
+        rooms.join(room(2, "b"), [10, 11]);
+        assert!(rooms.is_current(2));
+        assert_eq!(rooms.find("a"), Some(1));
+        assert_eq!(rooms.find("2"), Some(2));
+        assert_eq!(rooms.find("c"), None);
+        let mut shared = rooms.shared(10);
+        shared.sort();
+        assert_eq!(shared, [1, 2]);
+        rooms.switch(1);
+        assert!(rooms.is_current(1));
+        assert!(rooms.leave(1).is_some());
+        assert!(rooms.is_current(2));
+        assert!(rooms.leave(1).is_none());
+    }
+
+    #[test]
+    fn receive_only_from_members() {
+        let mut rooms = Rooms::new(10);
+        rooms.join(room(1, "a"), [10]);
+        rooms.join(room(2, "b"), [11]);
+        // 不在这个房间的成员发来的消息
+        assert!(matches!(rooms.receive(11, msg(1, 1, 11)), Received::Ignored));
+        assert!(matches!(rooms.receive(11, msg(2, 2, 11)), Received::Show));
+        assert!(matches!(rooms.receive(11, msg(2, 2, 11)), Received::Ignored));
+        match rooms.receive(10, msg(3, 1, 10)) {
+            Received::Unseen { name, count } => assert_eq!((name.as_str(), count), ("a", 1)),
+            _ => panic!("其他房间的消息应该记为未读"),
+        }
+        // 切换过去时给出没看的消息
+        let unseen = rooms.switch(1);
+        assert_eq!(unseen.iter().map(|m| m.id).collect::<Vec<_>>(), [3]);
+        assert_eq!(rooms.get(1).unwrap().unseen, 0);
+    }
+
+    #[test]
+    fn merge_and_recent() {
+        let mut rooms = Rooms::new(10);
+        rooms.join(room(1, "a"), [10]);
+        rooms.join(room(2, "b"), [10, 11]);
+        let (shown, others) = rooms.merge(10, vec![msg(1, 1, 10), msg(2, 2, 11), msg(3, 3, 10)]);
+        assert_eq!(shown.iter().map(|m| m.id).collect::<Vec<_>>(), [2]);
+        assert_eq!(others, 1);
+        assert_eq!(rooms.recent(1, 10, 10).len(), 1);
+        // 对方不在房间1中
+        assert!(rooms.recent(1, 11, 10).is_empty());
+        assert!(rooms.msg(2).is_some());
+        assert!(rooms.peer_left(1, 10));
+        assert!(!rooms.peer_left(2, 10));
+        assert!(!rooms.is_member(2, 10));
+    }
+
+    #[test]
+    fn unseen_across_rooms() {
+        let mut rooms = Rooms::new(10);
+        rooms.join(room(3, "c"), [10]);
+        rooms.join(room(1, "a"), [10]);
+        rooms.join(room(2, "b"), [10]);
+        rooms.receive(10, msg(1, 1, 10));
+        rooms.receive(10, msg(2, 1, 10));
+        rooms.receive(10, msg(3, 3, 10));
+        assert_eq!(rooms.list().iter().map(|v| (v.room.id, v.unseen)).collect::<Vec<_>>(), [(1, 2), (2, 0), (3, 1)]);
+        // 切换到没有加入的房间不改变当前房间
+        assert!(rooms.switch(9).is_empty());
+        assert!(rooms.is_current(2));
+        // 离开当前房间后切换到ID最小的房间，未读消息留到切换过去时再显示
+        rooms.leave(2);
+        assert!(rooms.is_current(1));
+        assert_eq!(rooms.get(1).unwrap().unseen, 2);
+        assert_eq!(rooms.switch(1).len(), 2);
+        assert!(rooms.switch(1).is_empty());
+        rooms.leave(1);
+        rooms.leave(3);
+        assert!(rooms.is_empty());
+        assert!(rooms.current().is_none());
+    }
+}
@@ -0,0 +1,1019 @@
+//! 与服务端的会话，以及处理服务端和用户操作的task
+
+use std::{
+    collections::HashMap, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, sync::Arc, time::Duration
+};
+use log::{debug, error, info, warn};
+use net::{self, BaseUserInfo, Room, ToPackage, TryRead, User, ClientInfo, ChatMsg, PeerPkg, LeaveRoom, Resume, RoomCmd, Secret, PunchReport, ServerPkg, Session, ID};
+use rand::Rng;
+use tokio::{
+    io::Result,
+    net::{TcpSocket, TcpStream},
+    sync::{broadcast, mpsc::{self, Sender, Receiver}, oneshot, Mutex},
+    task::JoinHandle,
+    time::{interval, sleep, timeout, Instant}
+};
+use crate::{
+    ChatState, Cmd, HEARTBEAT, HISTORY_LIMIT, PEER_CHECK, RECEIPT_TIMEOUT,
+    RECONNECT_MAX, RECONNECT_MIN, RECONNECT_TIMEOUT, RELAY_TTL,
+};
+use crate::lan;
+use crate::peer::{punch, spawn_peer, PeerCmd, PeerInfo};
+use crate::receipts::Receipts;
+use crate::redial::Redial;
+use crate::rooms::Rooms;
+
+/// 会话中发生的事情，按发生的顺序从[`ChatSession`]返回的接收端取出
+#[derive(serde::Serialize)]
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// 收到房间内的聊天消息，current为false时是其他房间的消息，切换过去时还会在History中给出
+    Message { msg: ChatMsg, current: bool },
+    /// 收到私信
+    Direct(ChatMsg),
+    /// 从其他成员同步过来的、或者切换房间时还没有看过的当前房间的聊天记录
+    History(Vec<ChatMsg>),
+    /// 加入了房间
+    Joined { room: ID, name: String },
+    /// 房间被关闭、被移出房间，或者服务器迁移后需要重新加入
+    Removed { room: ID, reason: String },
+    /// 被服务器踢出，之后不会再重连，已连接的成员仍然可以聊天，由调用者决定是否结束会话
+    Kicked(String),
+    /// 有成员加入了自己所在的房间
+    PeerJoined { room: ID, peer: BaseUserInfo },
+    /// 有成员离开了自己所在的房间
+    PeerLeft { room: ID, user: BaseUserInfo, reason: String },
+    /// 与某个成员建立了连接
+    PeerConnected(BaseUserInfo),
+    /// 与某个成员的连接断开
+    PeerDisconnected(BaseUserInfo),
+    /// 与服务端的连接状态变化
+    Connection(ConnState),
+    /// 服务器公告，room为None时是发给所有人的
+    Notice { room: Option<ID>, text: String },
+    /// 操作的结果：服务端对指令的回复，或者在本地就失败的原因，例如还没有加入房间、私信的对象没有连接
+    CmdReply { ok: bool, text: String },
+}
+
+/// 与服务端的连接状态
+#[derive(serde::Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    Online,
+    /// 断线后正在重连，已连接的成员仍然可以聊天
+    Reconnecting,
+    /// 不会再重连，比如会话已失效、被踢出或者服务器已关闭
+    Offline,
+}
+
+/// 加入房间的结果，失败时为原因
+type JoinReply = oneshot::Sender<std::result::Result<Room, String>>;
+
+/// 发给处理服务端的task的操作
+enum Request {
+    Join(Room, JoinReply),
+    Chat(String),
+    Cmd(Cmd),
+    /// 用户看过的消息ID，发送已读回执
+    Read(Vec<u64>),
+}
+
+/// 登录后的聊天会话，所有操作都交给后台的task处理
+pub struct ChatSession {
+    // 迁移到新服务器后ID会变化
+    user: Arc<std::sync::Mutex<User>>,
+    req_tx: Sender<Request>,
+    handle: JoinHandle<()>,
+    peers: Arc<Mutex<Vec<PeerInfo>>>,
+    chat: Arc<Mutex<ChatState>>,
+}
+
+impl ChatSession {
+    /// 连接服务端并登录，还没有加入任何房间
+    ///
+    /// 用户名或密码错误时返回InvalidInput，被封禁或失败次数过多时返回PermissionDenied，
+    /// 服务器繁忙或者连接太多时返回ConnectionRefused
+    pub async fn login(server_addr: &str, user: &User) -> Result<(Self, Receiver<Event>)> {
+        let mut stm = connect(server_addr).await.map_err(|e| {
+            std::io::Error::new(e.kind(), format!("无法连接到服务器。{}", e))
+        })?;
+        info!("已连接服务器。");
+        let (user, token) = login(&mut stm, user).await?;
+        Ok(Self::start(Some(ServerConn { stm, addr: server_addr.into(), token }), None, user, []))
+    }
+
+    /// 不连接服务端，在局域网中寻找同一个房间的成员，房间密码可以为空，只和密码相同的成员连接
+    pub async fn lan(target: SocketAddr, name: &str, room: &str, passwd: &str) -> Result<(Self, Receiver<Event>)> {
+        let (user, lan) = lan::start(name.into(), room.into(), passwd.into(), target).await?;
+        info!("进入房间：{}，正在寻找局域网中的其他成员", lan.room());
+        let room = Room { id: lan::ROOM, name: lan.room().into(), ..Default::default() };
+        Ok(Self::start(None, Some(lan), user, [room]))
+    }
+
+    fn start(server: Option<ServerConn>, lan: Option<lan::Lan>, user: User, rooms: impl IntoIterator<Item = Room>
+    ) -> (Self, Receiver<Event>) {
+        let (ev_tx, ev_rx) = mpsc::channel(128);
+        let (req_tx, req_rx) = mpsc::channel(16);
+        // 发往所有peer的数据包
+        let (out_tx, _) = broadcast::channel::<PeerPkg>(64);
+        let mut state = ChatState {
+            rooms: Rooms::new(HISTORY_LIMIT),
+            receipts: Receipts::new(HISTORY_LIMIT),
+            connected: HashMap::new(),
+        };
+        for room in rooms {
+            state.rooms.join(room, []);
+        }
+        let chat = Arc::new(Mutex::new(state));
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let user = Arc::new(std::sync::Mutex::new(user));
+        let handle = tokio::spawn(handle_server(
+            server, lan, user.clone(), ev_tx, peers.clone(), req_rx, out_tx, chat.clone()
+        ));
+        (Self { user, req_tx, handle, peers, chat }, ev_rx)
+    }
+
+    /// 登录后的用户信息，ID由服务端分配，迁移到新的服务器后会重新分配
+    pub fn user(&self) -> User {
+        self.user.lock().unwrap().clone()
+    }
+
+    /// 加入房间，房间名和密码或者邀请码二选一，失败时返回原因
+    pub async fn join(&self, room: Room) -> std::result::Result<Room, String> {
+        let (tx, rx) = oneshot::channel();
+        if self.req_tx.send(Request::Join(room, tx)).await.is_err() {
+            return Err("会话已结束".into());
+        }
+        rx.await.unwrap_or_else(|_| Err("与服务器的连接已断开".into()))
+    }
+
+    /// 向当前房间发送消息，这里只返回会话是否已结束，发送失败的原因在[`Event::CmdReply`]中给出
+    pub async fn send(&self, body: impl Into<String>) -> Result<()> {
+        self.request(Request::Chat(body.into())).await
+    }
+
+    /// 给一个已连接的成员发私信
+    pub async fn dm(&self, name: impl Into<String>, body: impl Into<String>) -> Result<()> {
+        self.command(Cmd::Dm { name: name.into(), body: body.into() }).await
+    }
+
+    /// 离开房间，None为当前房间
+    pub async fn leave(&self, room: Option<String>) -> Result<()> {
+        self.command(Cmd::Leave(room)).await
+    }
+
+    /// 执行`:`开头的指令，结果和失败的原因通过[`Event::CmdReply`]给出
+    pub async fn command(&self, cmd: Cmd) -> Result<()> {
+        self.request(Request::Cmd(cmd)).await
+    }
+
+    /// 告诉发送者这些消息已经看过了
+    pub async fn read(&self, ids: Vec<u64>) -> Result<()> {
+        self.request(Request::Read(ids)).await
+    }
+
+    /// 自己发出的消息中，还没有送达发送时已连接的所有成员的条数
+    pub async fn unacked(&self) -> usize {
+        self.chat.lock().await.receipts.pending()
+    }
+
+    async fn request(&self, req: Request) -> Result<()> {
+        self.req_tx.send(req).await.map_err(|_| std::io::ErrorKind::NotConnected.into())
+    }
+
+    /// 结束会话，等待所有连接关闭，超时后强制断开
+    pub async fn close(self) {
+        let Self { req_tx, handle, peers, .. } = self;
+        drop(req_tx);
+        // 去掉发给peer的指令通道，peer处理完手上的数据包后就会退出
+        let peers: Vec<_> = std::mem::take(&mut *peers.lock().await).into_iter()
+            .map(|p| (p.ci, p.handle))
+            .collect();
+        for _ in 0..50 {
+            if handle.is_finished() && peers.iter().all(|(_, h)| h.is_finished()) {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        // 强制关闭所有task
+        for (ci, h) in peers {
+            if !h.is_finished() {
+                warn!("强制断开 {:?}", ci);
+                h.abort();
+            }
+        }
+        handle.abort();
+    }
+}
+
+/// 登录服务端，返回用户信息和用来恢复会话的token
+async fn login(serv: &mut TcpStream, user: &User) -> Result<(User, Secret)> {
+    net::write(serv, &user.package().unwrap()).await?;
+    let stat = net::read(serv).await.map_err(io_error)?;
+    let stat = String::from_utf8_lossy(&stat);
+    if stat.contains("OK") {
+        let session: Session = serde_json::from_slice(&net::read(serv).await.map_err(io_error)?)?;
+        let user = User { id: session.id, ..user.clone() };
+        info!("登录成功, ID: {}", user.id);
+        return Ok((user, session.token));
+    }
+    let (kind, reason) = if stat.contains("banned") {
+        (std::io::ErrorKind::PermissionDenied, "已被服务器封禁")
+    } else if stat.contains("Server busy") {
+        (std::io::ErrorKind::ConnectionRefused, "服务器繁忙，请稍后再试")
+    } else if stat.contains("Too many connections") {
+        (std::io::ErrorKind::ConnectionRefused, "来自这个地址的连接太多，请稍后再试")
+    } else if stat.contains("Too many attempts") {
+        (std::io::ErrorKind::PermissionDenied, "登录失败次数过多，请稍后再试")
+    } else {
+        (std::io::ErrorKind::InvalidInput, "请输入正确的用户！")
+    };
+    Err(std::io::Error::new(kind, reason))
+}
+
+/// 进入房间时显示房间信息
+fn print_room(room: &Room) {
+    info!("进入房间：{:?}", room);
+    if !room.topic.is_empty() {
+        info!("话题：{}", room.topic);
+    }
+    if !room.description.is_empty() {
+        info!("介绍：{}", room.description);
+    }
+}
+
+/// 与服务端的连接，以及断线后重连需要的信息
+struct ServerConn {
+    stm: TcpStream,
+    // 服务端地址
+    addr: String,
+    // 用来恢复会话
+    token: Secret,
+}
+
+/// 从本机随机端口连接服务端，之后也用这个端口和其他成员连接
+async fn connect(server_addr: &str) -> Result<TcpStream> {
+    let port = rand::thread_rng().gen_range(4000..9000);
+    let loc_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
+    let sock = TcpSocket::new_v4()?;
+    #[cfg(target_family = "unix")]
+    {sock.set_reuseport(true)?;}
+    sock.set_reuseaddr(true)?;
+    // 绑定本地地址和端口
+    sock.bind(loc_addr)?;
+    let addr = server_addr.parse().map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    sock.connect(addr).await
+}
+
+/// 从原来的本地地址连接服务端，其他成员仍然可以通过它连接自己
+async fn reconnect(server_addr: &str, loc_addr: SocketAddr) -> Result<TcpStream> {
+    let sock = TcpSocket::new_v4()?;
+    #[cfg(target_family = "unix")]
+    {sock.set_reuseport(true)?;}
+    sock.set_reuseaddr(true)?;
+    sock.bind(loc_addr)?;
+    let addr = server_addr.parse().map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    sock.connect(addr).await
+}
+
+/// 断线后重新连接服务端，用token恢复之前的会话
+/// 会话已经失效时返回NotFound，不用再重试
+async fn resume(server_addr: &str, loc_addr: SocketAddr, token: &Secret) -> Result<(TcpStream, Session)> {
+    let mut stm = reconnect(server_addr, loc_addr).await?;
+    let resume = Resume { token: token.clone() };
+    net::write(&mut stm, &serde_json::to_vec(&resume)?).await?;
+    let stat = net::read(&mut stm).await.map_err(io_error)?;
+    // 被服务端限流或者服务端繁忙时稍后重试
+    if stat.starts_with(b"Too many") || stat == b"Server busy" {
+        return Err(std::io::ErrorKind::ConnectionRefused.into());
+    }
+    if !String::from_utf8_lossy(&stat).contains("OK") {
+        return Err(std::io::ErrorKind::NotFound.into());
+    }
+    let session = serde_json::from_slice(&net::read(&mut stm).await.map_err(io_error)?)?;
+    Ok((stm, session))
+}
+
+/// 服务器关闭时指定了新的地址，新服务器上没有原来的会话，用原来的用户名和密码重新登录
+async fn relogin(server_addr: &str, loc_addr: SocketAddr, user: &User) -> Result<(TcpStream, Session)> {
+    let mut stm = reconnect(server_addr, loc_addr).await?;
+    let (user, token) = login(&mut stm, user).await?;
+    Ok((stm, Session { id: user.id, name: user.name, token }))
+}
+
+fn io_error(e: net::ErrorType) -> std::io::Error {
+    match e {
+        net::ErrorType::IO(e) => e,
+        _ => std::io::ErrorKind::Other.into(),
+    }
+}
+
+/// 等待局域网中的成员，不是局域网模式时一直等待
+async fn lan_event(lan: &mut Option<lan::Lan>) -> lan::Event {
+    match lan {
+        Some(lan) => lan.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 等待连接可读，断线时一直等待
+async fn readable(stm: &Option<TcpStream>) -> Result<()> {
+    match stm {
+        Some(stm) => stm.readable().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_server(server: Option<ServerConn>, mut lan: Option<lan::Lan>, user: Arc<std::sync::Mutex<User>>,
+        ev_tx: Sender<Event>, clients: Arc<Mutex<Vec<PeerInfo>>>, mut req_rx: Receiver<Request>,
+        out_tx: broadcast::Sender<PeerPkg>, chat: Arc<Mutex<ChatState>>
+) {
+    let mut user_info = user.lock().unwrap().clone();
+    // 局域网模式下没有服务端，本地地址也不会用到
+    let (server_stream, mut server_addr, mut token) = match server {
+        Some(ServerConn { stm, addr, token }) => (Some(stm), addr, token),
+        None => (None, String::new(), Secret::default()),
+    };
+    let addr = match &server_stream {
+        Some(stm) => stm.local_addr().unwrap(),
+        None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    };
+    // 断线时为None，这时仍然可以和已连接的成员聊天
+    let mut server_stream = server_stream;
+    let mut reader = TryRead::new();
+    let mut heartbeat = interval(HEARTBEAT);
+    // 会话失效后不再重连
+    let mut resumable = server_stream.is_some();
+    // 服务器关闭后迁移到了新的地址，需要重新登录
+    let mut moved = false;
+    let mut backoff = RECONNECT_MIN;
+    let retry = sleep(Duration::ZERO);
+    tokio::pin!(retry);
+    // 加入房间时，等待服务端的回复
+    let mut joining: Option<Joining> = None;
+    // 正在连接的成员
+    let mut connecting = Connecting::new();
+    // 连接意外断开的成员，通过服务端协调重新连接
+    let mut redial = Redial::default();
+    let mut peer_check = interval(PEER_CHECK);
+    'a: loop {
+        tokio::select! {
+            _ = heartbeat.tick(), if server_stream.is_some() => {
+                debug!("server 发送心跳包");
+                let stm = server_stream.as_mut().unwrap();
+                if let Err(e) = net::write(stm, "".as_bytes()).await {
+                    warn!("与服务器的连接已断开：{}，正在重连...", e);
+                    server_stream = None;
+                    retry.as_mut().reset(Instant::now() + backoff);
+                    ev_tx.send(Event::Connection(ConnState::Reconnecting)).await.ok();
+                }
+            },
+            _ = &mut retry, if server_stream.is_none() && resumable => {
+                let res = if moved {
+                    timeout(RECONNECT_TIMEOUT, relogin(&server_addr, addr, &user_info)).await
+                } else {
+                    timeout(RECONNECT_TIMEOUT, resume(&server_addr, addr, &token)).await
+                };
+                match res {
+                    Ok(Ok((stm, session))) if moved => {
+                        // 新服务器分配了新的ID，原来的房间和成员都不在新服务器上，需要重新加入
+                        info!("已连接新的服务器{}, ID: {}", server_addr, session.id);
+                        moved = false;
+                        user_info.id = session.id;
+                        user.lock().unwrap().id = session.id;
+                        token = session.token;
+                        server_stream = Some(stm);
+                        reader = TryRead::new();
+                        joining = None;
+                        backoff = RECONNECT_MIN;
+                        redial = Redial::default();
+                        let rooms: Vec<ID> = chat.lock().await.rooms.list().iter().map(|v| v.room.id).collect();
+                        for room in rooms {
+                            chat.lock().await.rooms.leave(room);
+                            let reason = format!("服务器已迁移到{}，需要重新加入房间", server_addr);
+                            ev_tx.send(Event::Removed { room, reason }).await.ok();
+                        }
+                        close_unshared(&clients, &chat).await;
+                        ev_tx.send(Event::Connection(ConnState::Online)).await.ok();
+                    },
+                    Ok(Ok((stm, session))) => {
+                        info!("已重新连接服务器, ID: {}", session.id);
+                        token = session.token;
+                        server_stream = Some(stm);
+                        reader = TryRead::new();
+                        joining = None;
+                        backoff = RECONNECT_MIN;
+                        ev_tx.send(Event::Connection(ConnState::Online)).await.ok();
+                    },
+                    Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                        error!("会话已失效，无法重新连接服务器，已连接的成员仍然可以聊天");
+                        resumable = false;
+                        ev_tx.send(Event::Connection(ConnState::Offline)).await.ok();
+                    },
+                    // 新服务器不接受原来的用户名和密码，或者已被封禁
+                    Ok(Err(e)) if moved && matches!(e.kind(), std::io::ErrorKind::InvalidInput | std::io::ErrorKind::PermissionDenied) => {
+                        error!("无法登录新的服务器{}：{}，已连接的成员仍然可以聊天", server_addr, e);
+                        resumable = false;
+                        ev_tx.send(Event::Connection(ConnState::Offline)).await.ok();
+                    },
+                    res => {
+                        if let Ok(Err(e)) = res {
+                            debug!("resume: {}", e);
+                        }
+                        warn!("重连服务器失败，{}秒后重试", backoff.as_secs());
+                        retry.as_mut().reset(Instant::now() + backoff);
+                        backoff = (backoff * 2).min(RECONNECT_MAX);
+                    },
+                }
+            },
+            res = readable(&server_stream) => {
+                if let Err(e) = res {
+                    error!("Fail to server_stream.readable() {}", e);
+                    break;
+                }
+                debug!("server readable");
+                let pkg = match reader.poll(server_stream.as_mut().unwrap()) {
+                    Ok(_) => {
+                        reader.package()
+                    },
+                    Err(e) => {
+                        if let Some(e) = e.can_continue() {
+                            warn!("与服务器的连接已断开：{:?}，正在重连...", e);
+                            server_stream = None;
+                            retry.as_mut().reset(Instant::now() + backoff);
+                            ev_tx.send(Event::Connection(ConnState::Reconnecting)).await.ok();
+                        }
+                        continue 'a;
+                    },
+                };
+                debug!("server read pkg done.");
+                if pkg.is_empty() {
+                    // 心跳包，不用管
+                    debug!("from server 心跳包");
+                    continue;
+                }
+                if let Ok(sp) = ServerPkg::from(&pkg) {
+                    match sp {
+                        ServerPkg::PeerJoined { room, peer } => {
+                            match chat.lock().await.rooms.get_mut(room) {
+                                Some(view) => view.members.insert(peer.id),
+                                None => continue,
+                            };
+                            share(&clients, room, peer.id).await;
+                            let peer = BaseUserInfo { id: peer.id, name: peer.name };
+                            ev_tx.send(Event::PeerJoined { room, peer }).await.ok();
+                        },
+                        ServerPkg::Punch { peer, delay, interval, attempts } => {
+                            let connected = clients.lock().await.iter()
+                                .any(|p| p.ci.id == peer.id && !p.handle.is_finished());
+                            if connected || chat.lock().await.rooms.shared(peer.id).is_empty() {
+                                redial.remove(peer.id);
+                                continue;
+                            }
+                            if redial.dial(peer.id) {
+                                debug!("{}毫秒后连接{:?}，最多{}次", delay, peer, attempts);
+                                let plan = (Duration::from_millis(delay), Duration::from_millis(interval), attempts);
+                                connecting.spawn(punch(addr, user_info.clone(), peer, plan));
+                            }
+                        },
+                        ServerPkg::Notice(text) => {
+                            info!("[服务器公告] {}", text);
+                            ev_tx.send(Event::Notice { room: None, text }).await.ok();
+                        },
+                        ServerPkg::Kicked(reason) => {
+                            // 会话已被服务端删除，不再重连，是否断开已连接的成员由调用者决定
+                            error!("已被服务器踢出：{}", reason);
+                            server_stream = None;
+                            resumable = false;
+                            ev_tx.send(Event::Kicked(reason)).await.ok();
+                            ev_tx.send(Event::Connection(ConnState::Offline)).await.ok();
+                        },
+                        ServerPkg::RoomClosed(rid) => {
+                            let view = chat.lock().await.rooms.leave(rid);
+                            if let Some(view) = view {
+                                warn!("房间{}已被服务器关闭", view.room.name);
+                                close_unshared(&clients, &chat).await;
+                                let reason = "房间已被服务器关闭".into();
+                                ev_tx.send(Event::Removed { room: rid, reason }).await.ok();
+                            }
+                        },
+                        ServerPkg::PeerLeft { room, user, reason } => {
+                            let (name, shared) = {
+                                let mut chat = chat.lock().await;
+                                let name = chat.rooms.get(room).map(|v| v.room.name.clone());
+                                (name, chat.rooms.peer_left(room, user.id))
+                            };
+                            if let Some(name) = name {
+                                info!("{}离开了房间{}（{}）", user.name, name, reason);
+                                ev_tx.send(Event::PeerLeft { room, user: user.clone(), reason }).await.ok();
+                            }
+                            if !shared {
+                                // 对方可能已经断线，不再等待这个连接
+                                let mut clients = clients.lock().await;
+                                clients.retain(|p| {
+                                    let left = p.ci.id == user.id && p.ci.name == user.name;
+                                    if left {
+                                        p.handle.abort();
+                                    }
+                                    !left
+                                });
+                            }
+                        },
+                        ServerPkg::RoomNotice { room, text } => {
+                            let name = chat.lock().await.rooms.get(room).map(|v| v.room.name.clone());
+                            info!("[{}] {}", name.unwrap_or_default(), text);
+                            ev_tx.send(Event::Notice { room: Some(room), text }).await.ok();
+                        },
+                        ServerPkg::RemovedFromRoom { room, reason } => {
+                            let view = chat.lock().await.rooms.leave(room);
+                            if let Some(view) = view {
+                                error!("已被移出房间{}：{}", view.room.name, reason);
+                                close_unshared(&clients, &chat).await;
+                                ev_tx.send(Event::Removed { room, reason }).await.ok();
+                            }
+                        },
+                        ServerPkg::CmdReply { ok, text } => {
+                            if ok {
+                                info!("{}", text);
+                            } else {
+                                warn!("{}", text);
+                            }
+                            ev_tx.send(Event::CmdReply { ok, text }).await.ok();
+                        },
+                        ServerPkg::Shutdown { reconnect } => {
+                            // 已经建立的连接不受影响，还可以继续聊天
+                            server_stream = None;
+                            match reconnect {
+                                Some(new_addr) => {
+                                    warn!("服务器已关闭，正在连接新的服务器{}...", new_addr);
+                                    server_addr = new_addr;
+                                    moved = true;
+                                    backoff = RECONNECT_MIN;
+                                    retry.as_mut().reset(Instant::now() + backoff);
+                                    ev_tx.send(Event::Connection(ConnState::Reconnecting)).await.ok();
+                                },
+                                None => {
+                                    warn!("服务器已关闭，已连接的成员仍然可以聊天");
+                                    resumable = false;
+                                    ev_tx.send(Event::Connection(ConnState::Offline)).await.ok();
+                                },
+                            }
+                        },
+                    }
+                    continue;
+                }
+                // 加入房间时服务端依次回复结果、房间信息和房间内的成员
+                match joining.take() {
+                    Some(Joining::Reply(tx)) => {
+                        let reply = String::from_utf8_lossy(&pkg);
+                        if reply.to_uppercase().contains("OK") {
+                            joining = Some(Joining::Room(tx));
+                        } else {
+                            tx.send(Err(join_error(&reply).into())).ok();
+                        }
+                    },
+                    Some(Joining::Room(tx)) => {
+                        match serde_json::from_slice::<Room>(&pkg) {
+                            Ok(room) => joining = Some(Joining::Members(room, tx)),
+                            Err(_) => warn!("Unknown Pakage {:?}", &pkg),
+                        }
+                    },
+                    Some(Joining::Members(room, tx)) => {
+                        let cis = match serde_json::from_slice::<Vec<ClientInfo>>(&pkg) {
+                            Ok(cis) => cis,
+                            Err(_) => {
+                                warn!("Unknown Pakage {:?}", &pkg);
+                                continue;
+                            },
+                        };
+                        print_room(&room);
+                        info!("房间中共有{}个人", cis.len());
+                        if !cis.is_empty() { info!("开始建立连接..."); }
+                        let (rid, name) = (room.id, room.name.clone());
+                        chat.lock().await.rooms.join(room.clone(), cis.iter().map(|ci| ci.id));
+                        // 还没有连接的成员等服务端安排连接的时间
+                        for ci in cis {
+                            share(&clients, rid, ci.id).await;
+                        }
+                        ev_tx.send(Event::Joined { room: rid, name }).await.ok();
+                        tx.send(Ok(room)).ok();
+                    },
+                    None => info!("Unknown Pakage {:?}", &pkg),
+                }
+            },
+            _ = peer_check.tick() => {
+                // 找出意外断开的连接，被abort的已经从列表中移除了
+                let finished: Vec<ClientInfo> = {
+                    let mut clients = clients.lock().await;
+                    let finished = clients.iter()
+                        .filter(|p| p.handle.is_finished())
+                        .map(|p| p.ci.clone())
+                        .collect();
+                    clients.retain(|p| !p.handle.is_finished());
+                    finished
+                };
+                // 局域网模式下收到下一次通告时会重新连接
+                if lan.is_some() {
+                    continue;
+                }
+                let chat = chat.lock().await;
+                for ci in finished {
+                    if !chat.rooms.shared(ci.id).is_empty() {
+                        info!("与{}的连接已断开，稍后尝试重新连接", ci.name);
+                        redial.dropped(ci);
+                    }
+                }
+                let stm = match server_stream.as_mut() {
+                    Some(stm) => stm,
+                    None => continue,
+                };
+                for ci in redial.due(Instant::now()) {
+                    if chat.rooms.shared(ci.id).is_empty() {
+                        redial.remove(ci.id);
+                        continue;
+                    }
+                    debug!("请求服务端协调重新连接{:?}", ci);
+                    net::write(stm, &net::Redial { redial: ci.id }.package().unwrap()).await.ok();
+                }
+            },
+            ev = lan_event(&mut lan) => {
+                let lan = lan.as_mut().unwrap();
+                match ev {
+                    lan::Event::Found(ci) => {
+                        let connected = clients.lock().await.iter()
+                            .any(|p| p.ci.id == ci.id && !p.handle.is_finished());
+                        if !connected && lan.pending.insert(ci.id) {
+                            info!("发现{}（{}），正在连接...", ci.name, ci.addr);
+                            connecting.spawn(lan::connect(user_info.clone(), lan.room_key(), ci));
+                        }
+                    },
+                    lan::Event::Accepted(stm, from) => {
+                        connecting.spawn(lan::accept(user_info.clone(), lan.room_key(), stm, from));
+                    },
+                }
+            },
+            res = connecting.join_next(), if !connecting.is_empty() => {
+                match res {
+                    Some(Ok(Ok((ci, stm)))) => {
+                        if redial.remove(ci.id) {
+                            info!("已重新连接{}", ci.name);
+                        }
+                        if let Some(stm) = server_stream.as_mut() {
+                            let report = PunchReport { peer: ci.id, ok: true };
+                            net::write(stm, &report.package().unwrap()).await.ok();
+                        }
+                        if let Some(lan) = lan.as_mut() {
+                            // 局域网模式下连接成功后才知道对方是谁
+                            lan.pending.remove(&ci.id);
+                            if let Some(view) = chat.lock().await.rooms.get_mut(lan::ROOM) {
+                                view.members.insert(ci.id);
+                            }
+                        }
+                        let peer = spawn_peer(&ci, stm, ev_tx.clone(), out_tx.clone(), chat.clone());
+                        clients.lock().await.push(peer);
+                    },
+                    Some(Ok(Err(ci))) => {
+                        redial.dialed(ci.id);
+                        if let Some(lan) = lan.as_mut() {
+                            // 下一次收到通告时重试
+                            lan.pending.remove(&ci.id);
+                        }
+                        // 将未成功连接的回馈给服务端，还在同一个房间中的稍后再试
+                        if let Some(stm) = server_stream.as_mut() {
+                            let report = PunchReport { peer: ci.id, ok: false };
+                            net::write(stm, &report.package().unwrap()).await.ok();
+                            if !chat.lock().await.rooms.shared(ci.id).is_empty() {
+                                redial.dropped(ci);
+                            }
+                        }
+                    },
+                    _ => {},
+                }
+            },
+            req = req_rx.recv() => {
+                let req = match req {
+                    Some(req) => req,
+                    None => break,
+                };
+                let (body, reply_to) = match req {
+                    Request::Chat(body) => (body, None),
+                    Request::Read(ids) => {
+                        out_tx.send(PeerPkg::Read(ids)).ok();
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Status { tag }) => {
+                        if let Err(e) = print_receipts(&chat, &user_info, tag).await {
+                            cmd_failed(&ev_tx, e).await;
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Send { name, path }) => {
+                        let clients = clients.lock().await;
+                        let peer = clients.iter()
+                            .find(|p| p.ci.name == name && !p.handle.is_finished());
+                        match peer {
+                            Some(peer) => {
+                                peer.tx.send(PeerCmd::SendFile(path)).await.ok();
+                            },
+                            None => cmd_failed(&ev_tx, format!("没有与{}建立连接", name)).await,
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Accept { tag }) => {
+                        // 由收到这个文件请求的peer处理
+                        for peer in clients.lock().await.iter() {
+                            peer.tx.send(PeerCmd::Accept(tag.clone())).await.ok();
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Decline { tag }) => {
+                        for peer in clients.lock().await.iter() {
+                            peer.tx.send(PeerCmd::Decline(tag.clone())).await.ok();
+                        }
+                        continue;
+                    },
+                    Request::Join(room, tx) => {
+                        join(room, tx, &chat, lan.is_some(), &mut joining, &mut server_stream).await;
+                        continue;
+                    },
+                    // `:join`失败时通过事件告诉调用者
+                    Request::Cmd(Cmd::Join(room)) => {
+                        let (tx, rx) = oneshot::channel();
+                        join(room, tx, &chat, lan.is_some(), &mut joining, &mut server_stream).await;
+                        let ev_tx = ev_tx.clone();
+                        tokio::spawn(async move {
+                            if let Ok(Err(e)) = rx.await {
+                                cmd_failed(&ev_tx, e).await;
+                            }
+                        });
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Dm { name, body }) => {
+                        let msg = ChatMsg {
+                            id: rand::random(),
+                            sender: BaseUserInfo { id: user_info.id, name: user_info.name.clone() },
+                            time: chrono::Local::now().timestamp_millis(),
+                            // 私信不属于任何房间
+                            room: 0,
+                            body,
+                            reply_to: None,
+                        };
+                        let clients = clients.lock().await;
+                        let peer = clients.iter()
+                            .find(|p| p.ci.name == name && !p.handle.is_finished());
+                        match peer {
+                            Some(peer) => {
+                                peer.tx.send(PeerCmd::Direct(msg)).await.ok();
+                            },
+                            None => cmd_failed(&ev_tx, format!("没有与{}建立连接", name)).await,
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Switch(None)) => {
+                        let chat = chat.lock().await;
+                        if chat.rooms.is_empty() {
+                            info!("还没有加入房间");
+                        }
+                        for v in chat.rooms.list() {
+                            let mark = if chat.rooms.is_current(v.room.id) { "*" } else { " " };
+                            let unseen = if v.unseen > 0 { format!("，{}条未读", v.unseen) } else { String::new() };
+                            info!("{}[{}] {}（{}人{}）", mark, v.room.id, v.room.name, v.members.len() + 1, unseen);
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Switch(Some(key))) => {
+                        let mut chat = chat.lock().await;
+                        match chat.rooms.find(&key) {
+                            Some(id) => {
+                                let msgs = chat.rooms.switch(id);
+                                info!("切换到房间{}", chat.rooms.get(id).unwrap().room.name);
+                                if !msgs.is_empty() {
+                                    ev_tx.send(Event::History(msgs)).await.ok();
+                                }
+                            },
+                            None => cmd_failed(&ev_tx, format!("没有加入房间{}", key)).await,
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Leave(key)) => {
+                        let id = {
+                            let chat = chat.lock().await;
+                            match key {
+                                Some(key) => chat.rooms.find(&key),
+                                None => chat.rooms.current().map(|v| v.room.id),
+                            }
+                        };
+                        let id = match id {
+                            Some(id) => id,
+                            None => {
+                                cmd_failed(&ev_tx, "没有加入这个房间".into()).await;
+                                continue;
+                            },
+                        };
+                        match server_stream.as_mut() {
+                            Some(stm) => {
+                                net::write(stm, &LeaveRoom { leave: id }.package().unwrap()).await.ok();
+                            },
+                            None => {
+                                cmd_failed(&ev_tx, "未连接服务器，无法离开房间".into()).await;
+                                continue;
+                            },
+                        }
+                        chat.lock().await.rooms.leave(id);
+                        close_unshared(&clients, &chat).await;
+                        if let Some(v) = chat.lock().await.rooms.current() {
+                            info!("当前房间：{}", v.room.name);
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Room(op)) => {
+                        let room = match chat.lock().await.rooms.current() {
+                            Some(v) => v.room.id,
+                            None => {
+                                cmd_failed(&ev_tx, "还没有加入房间".into()).await;
+                                continue;
+                            },
+                        };
+                        let cmd = RoomCmd { room, op };
+                        match server_stream.as_mut() {
+                            Some(stm) => {
+                                net::write(stm, &cmd.package().unwrap()).await.ok();
+                            },
+                            None => cmd_failed(&ev_tx, "未连接服务器，无法执行".into()).await,
+                        }
+                        continue;
+                    },
+                    Request::Cmd(Cmd::Reply { tag, body }) => {
+                        let id = chat.lock().await.rooms.current()
+                            .and_then(|v| v.history.find_by_tag(&tag))
+                            .map(|m| m.id);
+                        if id.is_none() {
+                            cmd_failed(&ev_tx, format!("找不到消息#{}", tag)).await;
+                            continue;
+                        }
+                        (body, id)
+                    },
+                };
+                let (room, members) = match chat.lock().await.rooms.current() {
+                    Some(v) => (v.room.id, v.members.clone()),
+                    None => {
+                        cmd_failed(&ev_tx, "还没有加入房间，输入 :join <房间名> <密码> 加入".into()).await;
+                        continue;
+                    },
+                };
+                // 在这里生成消息，保证发给每个peer的消息ID相同
+                let msg = ChatMsg {
+                    id: rand::random(),
+                    sender: BaseUserInfo { id: user_info.id, name: user_info.name.clone() },
+                    time: chrono::Local::now().timestamp_millis(),
+                    room,
+                    body,
+                    reply_to,
+                };
+                let (id, tag) = (msg.id, msg.tag());
+                if let Some(view) = chat.lock().await.rooms.get_mut(room) {
+                    view.history.insert(msg.clone());
+                }
+                // 每个peer只转发给同一个房间的成员，送达数为这个房间内已连接的成员数
+                let connected: Vec<ID> = clients.lock().await.iter()
+                    .filter(|p| members.contains(&p.ci.id) && !p.handle.is_finished())
+                    .map(|p| p.ci.id)
+                    .collect();
+                let total = connected.len();
+                if total < members.len() {
+                    // 有连接不上的成员，让已连接的成员转发给他们
+                    let mut seen = connected.clone();
+                    seen.push(user_info.id);
+                    out_tx.send(PeerPkg::Relay { msg, ttl: RELAY_TTL, seen, to: connected }).ok();
+                } else {
+                    out_tx.send(PeerPkg::Chat(msg)).ok();
+                }
+                chat.lock().await.receipts.sent(id, total);
+                if total != 0 {
+                    // 一段时间后检查是否所有人都收到了
+                    let chat = chat.clone();
+                    tokio::spawn(async move {
+                        sleep(RECEIPT_TIMEOUT).await;
+                        if let Some(r) = chat.lock().await.receipts.get(id) {
+                            if r.delivered.len() < r.total {
+                                warn!("消息#{} 只送达了{}/{}，可能有连接已断开",
+                                        tag, r.delivered.len(), r.total);
+                            }
+                        }
+                    });
+                }
+            },
+        }
+    }
+    info!("Server disconnent.");
+    if resumable {
+        ev_tx.send(Event::Connection(ConnState::Offline)).await.ok();
+    }
+}
+
+/// 向服务端发送加入房间的请求，之后的回复由Joining处理
+async fn join(room: Room, tx: JoinReply, chat: &Arc<Mutex<ChatState>>, lan: bool,
+        joining: &mut Option<Joining>, server_stream: &mut Option<TcpStream>
+) {
+    let joined = chat.lock().await.rooms.list().iter().any(|v| v.room.name == room.name);
+    if joined {
+        tx.send(Err(format!("已经在房间{}中，输入 :room {} 切换", room.name, room.name))).ok();
+    } else if lan {
+        tx.send(Err("局域网模式下只能在一个房间中".into())).ok();
+    } else if joining.is_some() {
+        tx.send(Err("正在加入其他房间，请稍候".into())).ok();
+    } else if let Some(stm) = server_stream.as_mut() {
+        net::write(stm, &room.package().unwrap()).await.ok();
+        *joining = Some(Joining::Reply(tx));
+    } else {
+        tx.send(Err("未连接服务器，无法加入房间".into())).ok();
+    }
+}
+
+/// 离开房间后，断开与不再有共同房间的peer的连接
+async fn close_unshared(clients: &Arc<Mutex<Vec<PeerInfo>>>, chat: &Arc<Mutex<ChatState>>) {
+    let chat = chat.lock().await;
+    clients.lock().await.retain(|p| {
+        let shared = !chat.rooms.shared(p.ci.id).is_empty();
+        if !shared {
+            p.handle.abort();
+        }
+        shared
+    });
+    if chat.rooms.is_empty() {
+        warn!("已经不在任何房间中，输入 :join <房间名> <密码> 加入房间");
+    }
+}
+
+/// 已经因为其他房间连接过的成员只需要同步这个房间的聊天记录，
+/// 还没有连接的等服务端发来Punch后再连接
+async fn share(clients: &Arc<Mutex<Vec<PeerInfo>>>, room: ID, id: ID) {
+    let clients = clients.lock().await;
+    if let Some(peer) = clients.iter().find(|p| p.ci.id == id && !p.handle.is_finished()) {
+        peer.tx.send(PeerCmd::Share(room)).await.ok();
+    }
+}
+
+/// 输出自己最近发出的消息的回执，找不到指定的消息时返回原因
+async fn print_receipts(chat: &Arc<Mutex<ChatState>>, user_info: &User, tag: Option<String>
+) -> std::result::Result<(), String> {
+    let chat = chat.lock().await;
+    let ids = match tag {
+        Some(tag) => {
+            match chat.rooms.current().and_then(|v| v.history.find_by_tag(&tag)) {
+                Some(msg) if msg.sender.id == user_info.id => vec![msg.id],
+                _ => return Err(format!("找不到自己发出的消息#{}", tag)),
+            }
+        },
+        None => chat.receipts.recent(10),
+    };
+    if ids.is_empty() {
+        info!("还没有发出过消息");
+    }
+    for id in ids {
+        let body = chat.rooms.msg(id).map_or("", |m| m.body.as_str());
+        let status = chat.receipts.get(id).map_or("未知".into(), |r| r.status());
+        info!("#{} {}: {}", net::id_tag(id), body, status);
+    }
+    Ok(())
+}
+
+/// 操作在本地就失败了，输出原因并通过事件告诉调用者
+async fn cmd_failed(ev_tx: &Sender<Event>, text: String) {
+    warn!("{}", text);
+    ev_tx.send(Event::CmdReply { ok: false, text }).await.ok();
+}
+
+
+/// 加入房间时等待的回复
+enum Joining {
+    Reply(JoinReply),
+    Room(JoinReply),
+    Members(Room, JoinReply),
+}
+
+/// 正在连接的成员，连接失败时返回对方的信息
+type Connecting = tokio::task::JoinSet<std::result::Result<(ClientInfo, TcpStream), ClientInfo>>;
+
+/// 服务端拒绝加入房间的原因
+fn join_error(reply: &str) -> &'static str {
+    if reply.contains("Already") {
+        "你已经在这个房间中了！"
+    } else if reply.contains("Banned") {
+        "你已被禁止进入这个房间！"
+    } else if reply.contains("full") {
+        "房间人数已满！"
+    } else if reply.contains("invite") {
+        "邀请码无效或已过期！"
+    } else if reply.contains("Too many joins") {
+        "加入房间太频繁，请稍后再试！"
+    } else if reply.contains("Too many rooms") {
+        "你创建的房间太多了！"
+    } else if reply.contains("does not exist") {
+        "房间不存在！"
+    } else {
+        "请确认房间信息是否正确！"
+    }
+}
+
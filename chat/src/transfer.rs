@@ -0,0 +1,439 @@
+use std::{collections::HashMap, io::{self, Read}, path::{Path, PathBuf}};
+use net::{FileOffer, PeerPkg};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+
+// 每个数据包携带的文件内容大小
+const CHUNK_SIZE: usize = 16 * 1024;
+// 接收的文件保存的目录
+const DOWNLOAD_DIR: &str = "downloads";
+// 每个peer最多同时有这么多个等待接收或正在接收的文件，超过的自动拒绝
+const MAX_INCOMING: usize = 8;
+
+/// 计算文件的SHA-256，大文件要算很久，在阻塞线程中读取和计算
+pub async fn sha256_file(path: PathBuf) -> io::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }).await?
+}
+
+/// 打开要发送的文件并计算哈希，在peer的task之外完成，之后用Transfers::add登记
+pub async fn prepare(path: PathBuf) -> io::Result<(File, FileOffer)> {
+    let file = File::open(&path).await?;
+    let size = file.metadata().await?.len();
+    let offer = FileOffer {
+        id: rand::random(),
+        name: path.file_name()
+            .map_or("unnamed".into(), |n| n.to_string_lossy().to_string()),
+        size,
+        sha256: sha256_file(path).await?,
+    };
+    Ok((file, offer))
+}
+
+/// 下载目录中还没有被占用的路径，同名文件已存在时改为`name (1).ext`，不覆盖已有的文件
+async fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if fs::symlink_metadata(&path).await.is_err() {
+        return path;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut n = 1;
+    loop {
+        let path = dir.join(format!("{} ({}){}", stem, n, ext));
+        if fs::symlink_metadata(&path).await.is_err() {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+pub fn human_size(size: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, units[unit])
+}
+
+/// 每增加10%报告一次进度
+#[derive(Debug, Default)]
+struct Progress {
+    last: u64,
+}
+
+impl Progress {
+    fn update(&mut self, done: u64, total: u64) -> Option<u64> {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if percent / 10 > self.last / 10 {
+            self.last = percent;
+            Some(percent)
+        } else {
+            None
+        }
+    }
+}
+
+/// 发送中的文件
+#[derive(Debug)]
+pub struct Outgoing {
+    pub offer: FileOffer,
+    file: File,
+    sent: u64,
+    // 对方同意接收后才开始发送
+    active: bool,
+    // 最后一个文件块已经发出
+    done: bool,
+    progress: Progress,
+}
+
+/// 接收中的文件
+#[derive(Debug)]
+pub struct Incoming {
+    pub offer: FileOffer,
+    dir: PathBuf,
+    file: Option<File>,
+    received: u64,
+    progress: Progress,
+}
+
+impl Incoming {
+    /// 未接收完的文件，以文件内容的哈希区分，对方重新发送时可以续传
+    fn part_path(&self) -> PathBuf {
+        let hash = self.offer.sha256.get(..16).unwrap_or(&self.offer.sha256);
+        self.dir.join(format!("{}.{}.part", self.name(), hash))
+    }
+
+    /// 只保留文件名，防止对方写到下载目录之外，对方可能是windows，两种分隔符都要去掉
+    fn name(&self) -> String {
+        match self.offer.name.rsplit(['/', '\\']).next() {
+            Some(name) if !matches!(name, "" | "." | "..") => name.into(),
+            _ => "unnamed".into(),
+        }
+    }
+
+    /// 已经下载了的字节数
+    pub async fn partial(&self) -> u64 {
+        fs::metadata(self.part_path()).await.map_or(0, |m| m.len().min(self.offer.size))
+    }
+
+    /// 接收完毕后校验文件，通过时保存到下载目录，返回是否通过以及保存的位置
+    /// 计算哈希很慢，在peer的task之外进行
+    pub async fn verify(self) -> io::Result<(bool, PathBuf)> {
+        let part = self.part_path();
+        if sha256_file(part.clone()).await? == self.offer.sha256 {
+            let name = unique_path(&self.dir, &self.name()).await;
+            fs::rename(&part, &name).await?;
+            Ok((true, name))
+        } else {
+            fs::remove_file(&part).await?;
+            Ok((false, self.dir.join(self.name())))
+        }
+    }
+}
+
+/// 接收文件块的结果
+pub enum ChunkResult {
+    Ignored,
+    Progress(u64),
+    /// 文件接收完毕，需要调用Incoming::verify校验
+    Complete(Incoming),
+}
+
+/// 文件读写失败，这个传输已经取消，需要告诉对方
+pub type Failed = (FileOffer, io::Error);
+
+/// 与一个peer之间的所有文件传输
+#[derive(Debug)]
+pub struct Transfers {
+    outgoing: HashMap<u64, Outgoing>,
+    incoming: HashMap<u64, Incoming>,
+    // 接收的文件保存的目录
+    dir: PathBuf,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Self::new(DOWNLOAD_DIR)
+    }
+}
+
+impl Transfers {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { outgoing: HashMap::new(), incoming: HashMap::new(), dir: dir.into() }
+    }
+
+    /// 登记用prepare准备好的文件，等待对方接收
+    pub fn add(&mut self, file: File, offer: FileOffer) {
+        self.outgoing.insert(offer.id, Outgoing {
+            offer,
+            file,
+            sent: 0,
+            active: false,
+            done: false,
+            progress: Progress::default(),
+        });
+    }
+
+    /// 是否有需要发送的文件块
+    pub fn sending(&self) -> bool {
+        self.outgoing.values().any(|o| o.active && !o.done)
+    }
+
+    /// 读取下一个要发送的文件块，同时返回发送进度，读取失败时取消这个文件的发送
+    pub async fn next_chunk(&mut self) -> Result<Option<(PeerPkg, Option<(String, u64)>)>, Failed> {
+        let out = match self.outgoing.values_mut().find(|o| o.active && !o.done) {
+            Some(out) => out,
+            None => return Ok(None),
+        };
+        let len = CHUNK_SIZE.min((out.offer.size - out.sent) as usize);
+        let mut data = vec![0u8; len];
+        if let Err(e) = out.file.read_exact(&mut data).await {
+            let id = out.offer.id;
+            return Err((self.outgoing.remove(&id).unwrap().offer, e));
+        }
+        let pkg = PeerPkg::FileChunk { id: out.offer.id, offset: out.sent, data };
+        out.sent += len as u64;
+        out.done = out.sent >= out.offer.size;
+        let progress = out.progress.update(out.sent, out.offer.size)
+            .map(|p| (out.offer.name.clone(), p));
+        Ok(Some((pkg, progress)))
+    }
+
+    /// 对方同意接收，从offset处开始发送
+    pub async fn accepted(&mut self, id: u64, offset: u64) -> Result<Option<&FileOffer>, Failed> {
+        let res = match self.outgoing.get_mut(&id) {
+            Some(out) => out.file.seek(SeekFrom::Start(offset.min(out.offer.size))).await,
+            None => return Ok(None),
+        };
+        let offset = match res {
+            Ok(offset) => offset,
+            Err(e) => return Err((self.outgoing.remove(&id).unwrap().offer, e)),
+        };
+        let out = self.outgoing.get_mut(&id).unwrap();
+        out.sent = offset;
+        out.active = true;
+        out.done = false;
+        Ok(Some(&out.offer))
+    }
+
+    /// 对方拒绝接收或者已经校验完成
+    pub fn finished(&mut self, id: u64) -> Option<Outgoing> {
+        self.outgoing.remove(&id)
+    }
+
+    /// 对方或者自己取消了传输，返回文件名
+    pub fn abort(&mut self, id: u64) -> Option<String> {
+        let offer = self.outgoing.remove(&id).map(|o| o.offer)
+            .or_else(|| self.incoming.remove(&id).map(|i| i.offer))?;
+        Some(offer.name)
+    }
+
+    /// 对方请求发送文件，同时等待接收的文件太多时返回None
+    pub fn offered(&mut self, offer: FileOffer) -> Option<&Incoming> {
+        let id = offer.id;
+        if !self.incoming.contains_key(&id) && self.incoming.len() >= MAX_INCOMING {
+            return None;
+        }
+        self.incoming.insert(id, Incoming {
+            offer,
+            dir: self.dir.clone(),
+            file: None,
+            received: 0,
+            progress: Progress::default(),
+        });
+        self.incoming.get(&id)
+    }
+
+    fn find_incoming(&self, tag: &str) -> Option<u64> {
+        self.incoming.values()
+            .find(|i| i.file.is_none() && net::id_tag(i.offer.id) == tag)
+            .map(|i| i.offer.id)
+    }
+
+    /// 同意接收文件，返回文件ID和续传的位置
+    pub async fn accept(&mut self, tag: &str) -> io::Result<Option<(u64, u64)>> {
+        let id = match self.find_incoming(tag) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        fs::create_dir_all(&self.dir).await?;
+        let inc = self.incoming.get_mut(&id).unwrap();
+        let offset = inc.partial().await;
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false)
+            .open(inc.part_path()).await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        inc.file = Some(file);
+        inc.received = offset;
+        Ok(Some((id, offset)))
+    }
+
+    /// 拒绝接收文件
+    pub fn decline(&mut self, tag: &str) -> Option<u64> {
+        let id = self.find_incoming(tag)?;
+        self.incoming.remove(&id);
+        Some(id)
+    }
+
+    /// 接收一个文件块，写入失败时取消这个文件的接收，已经接收的部分保留，下次可以继续
+    pub async fn chunk(&mut self, id: u64, offset: u64, data: &[u8]) -> Result<ChunkResult, Failed> {
+        let inc = match self.incoming.get_mut(&id) {
+            Some(inc) => inc,
+            None => return Ok(ChunkResult::Ignored),
+        };
+        let file = match inc.file.as_mut() {
+            Some(file) if offset == inc.received => file,
+            _ => return Ok(ChunkResult::Ignored),
+        };
+        let mut res = file.write_all(data).await;
+        inc.received += data.len() as u64;
+        if res.is_ok() && inc.received < inc.offer.size {
+            return Ok(match inc.progress.update(inc.received, inc.offer.size) {
+                Some(p) => ChunkResult::Progress(p),
+                None => ChunkResult::Ignored,
+            });
+        }
+        let mut inc = self.incoming.remove(&id).unwrap();
+        if let (Ok(_), Some(mut file)) = (&res, inc.file.take()) {
+            res = file.flush().await;
+        }
+        match res {
+            Ok(_) => Ok(ChunkResult::Complete(inc)),
+            Err(e) => Err((inc.offer, e)),
+        }
+    }
+
+    pub fn incoming_name(&self, id: u64) -> Option<String> {
+        self.incoming.get(&id).map(|i| i.offer.name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 每个测试用单独的临时目录，里面有一个要发送的文件
+    async fn setup(name: &str, len: usize) -> (PathBuf, PathBuf, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!("chat-transfer-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).await.ok();
+        fs::create_dir_all(&dir).await.unwrap();
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let src = dir.join("photo.jpg");
+        fs::write(&src, &data).await.unwrap();
+        (dir.join("downloads"), src, data)
+    }
+
+    /// 发送端把文件块交给接收端，直到接收完毕或者发送了max块
+    async fn pump(out: &mut Transfers, inc: &mut Transfers, id: u64, max: usize) -> Option<Incoming> {
+        for _ in 0..max {
+            let (pkg, _) = out.next_chunk().await.unwrap()?;
+            if let PeerPkg::FileChunk { offset, data, .. } = pkg {
+                if let ChunkResult::Complete(done) = inc.chunk(id, offset, &data).await.unwrap() {
+                    return Some(done);
+                }
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn resume_and_verify() {
+        let (dir, src, data) = setup("resume", CHUNK_SIZE * 3 + 100).await;
+        let (file, offer) = prepare(src.clone()).await.unwrap();
+        assert_eq!(offer.size, data.len() as u64);
+        assert_eq!(offer.sha256, sha256_file(src.clone()).await.unwrap());
+        let id = offer.id;
+        let tag = net::id_tag(id);
+        let mut out = Transfers::new(&dir);
+        out.add(file, offer.clone());
+        let mut inc = Transfers::new(&dir);
+        inc.offered(offer.clone()).unwrap();
+        assert_eq!(inc.accept(&tag).await.unwrap(), Some((id, 0)));
+        out.accepted(id, 0).await.unwrap().unwrap();
+        // 收到两块后断开
+        assert!(pump(&mut out, &mut inc, id, 2).await.is_none());
+        drop((out, inc));
+        // 重新发送同一个文件，从已经收到的位置继续
+        let (file, _) = prepare(src.clone()).await.unwrap();
+        let mut out = Transfers::new(&dir);
+        out.add(file, offer.clone());
+        let mut inc = Transfers::new(&dir);
+        assert_eq!(inc.offered(offer.clone()).unwrap().partial().await, CHUNK_SIZE as u64 * 2);
+        assert_eq!(inc.accept(&tag).await.unwrap(), Some((id, CHUNK_SIZE as u64 * 2)));
+        out.accepted(id, CHUNK_SIZE as u64 * 2).await.unwrap().unwrap();
+        let done = pump(&mut out, &mut inc, id, 10).await.unwrap();
+        let (ok, path) = done.verify().await.unwrap();
+        assert!(ok);
+        assert_eq!(path, dir.join("photo.jpg"));
+        assert_eq!(fs::read(&path).await.unwrap(), data);
+        assert!(!out.sending());
+        // 再收一次同名文件不会覆盖已有的
+        let (file, _) = prepare(src).await.unwrap();
+        out.add(file, offer.clone());
+        inc.offered(offer).unwrap();
+        inc.accept(&tag).await.unwrap();
+        out.accepted(id, 0).await.unwrap();
+        let (ok, path) = pump(&mut out, &mut inc, id, 10).await.unwrap().verify().await.unwrap();
+        assert!(ok);
+        assert_eq!(path, dir.join("photo (1).jpg"));
+        fs::remove_dir_all(dir.parent().unwrap()).await.ok();
+    }
+
+    #[tokio::test]
+    async fn bad_sha() {
+        let (dir, src, _) = setup("sha", 1000).await;
+        let (file, mut offer) = prepare(src).await.unwrap();
+        offer.sha256 = "00".repeat(32);
+        // 对方的文件名只保留最后一段
+        offer.name = "..\\../evil.txt".into();
+        let mut out = Transfers::new(&dir);
+        out.add(file, offer.clone());
+        let mut inc = Transfers::new(&dir);
+        inc.offered(offer.clone()).unwrap();
+        inc.accept(&net::id_tag(offer.id)).await.unwrap();
+        out.accepted(offer.id, 0).await.unwrap();
+        let (ok, path) = pump(&mut out, &mut inc, offer.id, 10).await.unwrap().verify().await.unwrap();
+        assert!(!ok);
+        assert_eq!(path, dir.join("evil.txt"));
+        // 校验失败的文件被删除
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        assert!(entries.next_entry().await.unwrap().is_none());
+        fs::remove_dir_all(dir.parent().unwrap()).await.ok();
+    }
+
+    #[test]
+    fn limits_and_abort() {
+        let offer = |id| FileOffer { id, name: "a".into(), size: 1, sha256: String::new() };
+        let mut inc = Transfers::new("unused");
+        for id in 0..MAX_INCOMING as u64 {
+            assert!(inc.offered(offer(id)).is_some());
+        }
+        assert!(inc.offered(offer(100)).is_none());
+        // 同一个文件重新发送不算新的
+        assert!(inc.offered(offer(0)).is_some());
+        assert_eq!(inc.abort(0).as_deref(), Some("a"));
+        assert_eq!(inc.abort(0), None);
+        assert!(inc.offered(offer(100)).is_some());
+        assert_eq!(inc.decline(&net::id_tag(100)), Some(100));
+    }
+}
@@ -0,0 +1,201 @@
+//! 非交互模式：登录信息和房间来自命令行参数，从标准输入逐行读取要发送的消息，
+//! 每个事件以一行JSON输出到标准输出，日志输出到标准错误，不需要终端
+
+use std::{collections::VecDeque, io::Write, net::SocketAddr, time::Duration};
+use chat::{ChatSession, Cmd, Event};
+use log::{error, info, warn};
+use net::{Room, User};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader, Error, ErrorKind, Result};
+use tokio::time::{interval, Instant};
+
+// 标准输入结束后，默认最多再等这么久，让还没发出去的消息发出去、已发出的消息送达
+const LINGER: Duration = Duration::from_secs(10);
+// 等待时检查消息是否都已送达的间隔
+const LINGER_CHECK: Duration = Duration::from_millis(200);
+
+/// 命令行指定的用户和房间
+#[derive(Default)]
+pub struct Options {
+    pub user: Option<String>,
+    pub passwd: String,
+    pub room: Option<String>,
+    pub room_passwd: String,
+    /// 标准输入结束后等待的时间，None为默认的LINGER
+    pub linger: Option<Duration>,
+}
+
+/// `:`开头的指令立即执行，还没有和任何成员连接时读到的消息先排队，连上第一个成员后再发送，否则消息没有人收到
+/// 标准输入结束后等到排队的消息都已发出、发出的消息都已送达（或者已经没有连接的成员），或者超过linger后关闭会话
+/// 会话结束时返回，登录或加入房间失败时返回错误
+pub async fn run(server_addr: &str, lan_addr: Option<SocketAddr>, invite: Option<String>, opts: Options
+) -> Result<()> {
+    let name = opts.user.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "--bot 需要用 --user 指定用户名"))?;
+    let (session, mut events) = match lan_addr {
+        Some(target) => {
+            let room = opts.room.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "局域网模式需要用 --room 指定房间名"))?;
+            ChatSession::lan(target, &name, &room, &opts.room_passwd).await?
+        },
+        None => {
+            let room = match (invite, opts.room) {
+                (Some(token), _) => Room { invite: token.into(), ..Default::default() },
+                (None, Some(room)) => Room { name: room, passwd: opts.room_passwd.into(), ..Default::default() },
+                (None, None) => return Err(Error::new(ErrorKind::InvalidInput, "需要用 --room 或 --invite 指定房间")),
+            };
+            let user = User { id: 0, name, passwd: opts.passwd.into() };
+            let (session, events) = ChatSession::login(server_addr, &user).await?;
+            if let Err(e) = session.join(room).await {
+                session.close().await;
+                return Err(Error::new(ErrorKind::InvalidInput, e));
+            }
+            (session, events)
+        },
+    };
+    let mut lines = BufReader::new(stdin()).lines();
+    let linger = opts.linger.unwrap_or(LINGER);
+    let mut queue = VecDeque::new();
+    let mut connected = 0usize;
+    // 标准输入结束的时间
+    let mut eof: Option<Instant> = None;
+    let mut check = interval(LINGER_CHECK);
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if eof.is_none() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => {
+                        info!("标准输入已结束");
+                        eof = Some(Instant::now());
+                        continue;
+                    },
+                    Err(e) => {
+                        error!("读取标准输入失败：{}", e);
+                        eof = Some(Instant::now());
+                        continue;
+                    },
+                };
+                let res = match Input::parse(&line) {
+                    // :join、:status等指令不用等待成员连接
+                    Some(Input::Cmd(cmd)) => command(&session, cmd).await,
+                    Some(Input::Chat(body)) => {
+                        queue.push_back(body);
+                        if connected > 0 { flush(&session, &mut queue).await } else { Ok(()) }
+                    },
+                    None => Ok(()),
+                };
+                if res.is_err() {
+                    break;
+                }
+            },
+            ev = events.recv() => {
+                let ev = match ev {
+                    Some(ev) => ev,
+                    None => break,
+                };
+                print_event(&ev);
+                match &ev {
+                    // 输出了就当作已读
+                    Event::Message { msg, current: true } => {
+                        session.read(vec![msg.id]).await.ok();
+                    },
+                    Event::PeerConnected(_) => {
+                        connected += 1;
+                        if flush(&session, &mut queue).await.is_err() {
+                            break;
+                        }
+                    },
+                    Event::PeerDisconnected(_) => connected = connected.saturating_sub(1),
+                    // 被踢出后不会再有新的成员连接，直接退出
+                    Event::Kicked(_) => break,
+                    _ => {},
+                }
+            },
+            _ = check.tick(), if eof.is_some() => {
+                // 没有连接的成员时已发出的消息不会再有确认
+                if queue.is_empty() && (connected == 0 || session.unacked().await == 0) {
+                    break;
+                }
+                if eof.is_some_and(|t| t.elapsed() >= linger) {
+                    if !queue.is_empty() {
+                        warn!("没有连接上任何成员，{}条消息没有发出", queue.len());
+                    } else {
+                        warn!("{}条消息没有确认送达", session.unacked().await);
+                    }
+                    break;
+                }
+            },
+        }
+    }
+    session.close().await;
+    Ok(())
+}
+
+/// 从标准输入读到的一行
+#[derive(Debug)]
+enum Input {
+    /// 和交互模式一样，`:`开头的是指令，无法识别时为原因
+    Cmd(std::result::Result<Cmd, String>),
+    Chat(String),
+}
+
+impl Input {
+    /// 空行返回None
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() {
+            None
+        } else if line.starts_with(':') {
+            Some(Self::Cmd(Cmd::parse(line)))
+        } else {
+            Some(Self::Chat(line.to_string()))
+        }
+    }
+}
+
+/// 执行指令，无法识别的指令输出为CmdReply，会话已结束时返回错误
+async fn command(session: &ChatSession, cmd: std::result::Result<Cmd, String>) -> Result<()> {
+    match cmd {
+        Ok(cmd) => session.command(cmd).await,
+        Err(e) => {
+            warn!("{}", e);
+            print_event(&Event::CmdReply { ok: false, text: e });
+            Ok(())
+        },
+    }
+}
+
+/// 按顺序发送排队的消息，会话已结束时返回错误
+async fn flush(session: &ChatSession, queue: &mut VecDeque<String>) -> Result<()> {
+    while let Some(body) = queue.pop_front() {
+        session.send(body).await?;
+    }
+    Ok(())
+}
+
+/// 一个事件输出为一行JSON
+fn print_event(ev: &Event) {
+    let mut out = std::io::stdout().lock();
+    match serde_json::to_string(ev) {
+        Ok(json) => {
+            writeln!(out, "{}", json).ok();
+            out.flush().ok();
+        },
+        Err(e) => error!("无法输出事件{:?}：{}", ev, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input() {
+        assert!(Input::parse("   ").is_none());
+        assert!(matches!(Input::parse("  hello  "), Some(Input::Chat(body)) if body == "hello"));
+        // 指令不进入消息队列
+        assert!(matches!(Input::parse(":status"), Some(Input::Cmd(Ok(Cmd::Status { tag: None })))));
+        assert!(matches!(Input::parse(" :join room2 pw"), Some(Input::Cmd(Ok(Cmd::Join(room)))) if room.name == "room2"));
+        assert!(matches!(Input::parse(":nope"), Some(Input::Cmd(Err(e))) if e.contains("nope")));
+        // 冒号不在开头的是普通消息
+        assert!(matches!(Input::parse("a :b"), Some(Input::Chat(_))));
+    }
+}
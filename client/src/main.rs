@@ -1,17 +1,16 @@
-use std::{
-    env, io::Write, mem::size_of, net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc, time::Duration
-};
+use std::{env, io::Write, mem::size_of, net::SocketAddr, time::Duration};
+use chat::{ChatSession, Cmd, Event};
 use env_logger::Builder;
 use log::{debug, error, info, warn, LevelFilter};
-use net::{self, BaseUserInfo, Room, ToPackage, TryRead, User, ClientInfo};
-use rand::Rng;
+use net::{ChatMsg, Room, User};
 use tokio::{
     io::Result,
-    net::{TcpSocket, TcpStream},
-    sync::{mpsc::{self, Sender, Receiver}, watch, Mutex},
-    time::sleep
+    sync::{mpsc::{self, Sender, Receiver}, watch},
+    time::timeout,
 };
+
+mod bot;
+
 // 默认服务器地址
 const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:5566";
 
@@ -24,9 +23,89 @@ async fn main() {
         }
         unsafe { system("chcp 65001\0".as_ptr() as *const std::ffi::c_char); }
     }
+    let mut server_addr: String = DEFAULT_SERVER_ADDR.into();
+    // 用邀请码加入房间
+    let mut invite = None;
+    // 局域网模式下通告发往的地址
+    let mut lan_addr: Option<SocketAddr> = None;
+    // 非交互模式
+    let mut bot = false;
+    let mut opts = bot::Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--invite" => {
+                invite = args.next();
+                if invite.is_none() {
+                    eprintln!("--invite 需要指定邀请码");
+                    return;
+                }
+            },
+            "--lan" => {
+                lan_addr.get_or_insert(chat::DEFAULT_LAN_ADDR.parse().unwrap());
+            },
+            "--lan-addr" => {
+                match args.next().and_then(|addr| addr.parse().ok()) {
+                    Some(addr) => lan_addr = Some(addr),
+                    None => {
+                        eprintln!("--lan-addr 需要指定广播或组播地址，例如 {}", chat::DEFAULT_LAN_ADDR);
+                        return;
+                    },
+                }
+            },
+            "--bot" => bot = true,
+            "--linger" => {
+                match args.next().and_then(|secs| secs.parse().ok()) {
+                    Some(secs) => opts.linger = Some(Duration::from_secs(secs)),
+                    None => {
+                        eprintln!("--linger 需要指定秒数");
+                        return;
+                    },
+                }
+            },
+            "--user" | "--passwd" | "--room" | "--room-passwd" => {
+                let value = match args.next() {
+                    Some(value) => value,
+                    None => {
+                        eprintln!("{} 需要指定一个值", arg);
+                        return;
+                    },
+                };
+                match arg.as_str() {
+                    "--user" => opts.user = Some(value),
+                    "--passwd" => opts.passwd = value,
+                    "--room" => opts.room = Some(value),
+                    _ => opts.room_passwd = value,
+                }
+            },
+            addr => server_addr = addr.into(),
+        }
+    }
+    if bot {
+        // 标准输出只用来输出事件，日志输出到标准错误
+        Builder::new()
+            .format(|buf, record| {
+                writeln!(buf,
+                    "[{} {}] {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    record.level(),
+                    record.args()
+                )
+            })
+            .filter(None, LevelFilter::Info)
+            .target(env_logger::Target::Stderr)
+            .init();
+        if let Err(e) = bot::run(&server_addr, lan_addr, invite, opts).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     let (msg_tx, msg_rx) = mpsc::channel::<Msg>(128);
-    let (cin_tx, mut cin_rx) = watch::channel(String::new());
-    let msg_handle = tokio::spawn(msg_handle(msg_rx));
+    let (cin_tx, cin_rx) = watch::channel(String::new());
+    // 用户看过的消息ID，由msg handle发给会话
+    let (read_tx, read_rx) = mpsc::channel::<Vec<u64>>(16);
+    let msg_handle = tokio::spawn(msg_handle(msg_rx, read_tx));
     let (log_tx, log_rx) = mpsc::channel::<String>(64);
     let log_handle = tokio::spawn(log_handle(log_rx, msg_tx.clone()));
     // 设置日志输出格式
@@ -50,87 +129,15 @@ async fn main() {
         .filter(None, LevelFilter::Info)
         .target(env_logger::Target::Pipe(Box::new(MyLogTarget::new(log_tx))))
         .init();
-    let mut server_addr: String = DEFAULT_SERVER_ADDR.into();
-    if env::args().len() > 1 {
-        server_addr = env::args().nth(1).unwrap();
-    }
-    // 本机随机端口
-    let loc_addr = {
-        let mut rng = rand::thread_rng();
-        let port = rng.gen_range(4000..9000);
-        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))
-    };
-    let mut server_stream = {
-        let server_sock = TcpSocket::new_v4().unwrap();
-        #[cfg(target_family = "unix")]
-        {server_sock.set_reuseport(true).unwrap();}
-        server_sock.set_reuseaddr(true).unwrap();
-        // 绑定本地地址和端口
-        server_sock.bind(loc_addr).unwrap();
-        match server_sock.connect(server_addr.parse().unwrap()).await {
-            Ok(sock) => { sock },
-            Err(e) => {
-                eprintln!("无法连接到服务器。{}", e);
-                return;
-            },
-        }
-    };
-    info!("已连接服务器。");
-    let msg_tx_clone = msg_tx.clone();
-    let peers: Arc<Mutex<Vec<PeerInfo>>> = Arc::new(Mutex::new(Vec::new()));
-    let peers_ = peers.clone();
-    // 用来向处理服务端的task发送退出指令
-    let (sh_tx, sh_rx) = tokio::sync::oneshot::channel();
-    let server_handle = tokio::spawn(async move {
-        // 登录
-        let user_info = if let Ok(ui) = login(&mut server_stream, &msg_tx_clone, &mut cin_rx).await {
-            ui
-        } else { return; };
-        // 在克隆前先将内容清空
-        cin_rx.borrow_and_update();
-        // 发送房间信息
-        let room = if let Ok(rom) = join_room(&mut server_stream, &msg_tx_clone, &mut cin_rx).await {
-            rom
-        } else { return; };
-        info!("进入房间：{:?}", &room);
-
-        cin_rx.borrow_and_update();
-        init_room(&mut server_stream, &user_info, &mut cin_rx, &msg_tx_clone).await;
-
-        handle_server(
-            server_stream, user_info, msg_tx_clone, peers_, cin_rx, sh_rx
-        ).await;
-    });
+    let mut session_handle = tokio::spawn(run(server_addr, lan_addr, invite, msg_tx.clone(), cin_rx, read_rx));
     // 主线程来监控标准输入
     poll_user_input(&cin_tx, &msg_tx).await;
     info!("正在等待所有任务结束");
-    if let Err(e) = sh_tx.send(true) {
-        error!("Server handle tx Send fail, {}", e);
-    };
-    for _ in 0..50 {
-        let _ = msg_tx.send(Msg::Other(".".to_string())).await;
-        let _ = cin_tx.send('\x03'.to_string());
-        let mut finish = true;
-        if !server_handle.is_finished() {
-            finish = false;
-        } else {
-            for peer in peers.lock().await.iter_mut() {
-                if !peer.handle.is_finished() {
-                    finish = false;
-                    break;
-                }
-            }
-        }
-        if finish {
-            break;
-        }
-        sleep(Duration::from_millis(100)).await;
+    let _ = cin_tx.send('\x03'.to_string());
+    // 会话关闭时最多等待5秒，超时后强制断开
+    if timeout(Duration::from_secs(6), &mut session_handle).await.is_err() {
+        session_handle.abort();
     }
-    // 强制关闭所有task
-    for peer in peers.lock().await.iter_mut() {
-        peer.handle.abort();
-    }
-    server_handle.abort();
     drop(msg_tx);
     log_handle.abort();
     tokio::try_join!(msg_handle).unwrap();
@@ -146,16 +153,14 @@ async fn poll_user_input(cin_tx: &watch::Sender<String>, msg_tx: &mpsc::Sender<M
             Ok(c) => { c },
             Err(_) => {continue;},
         };
-        if ch_buf_len == 0 {
-            if c == 3 {
-                msg_tx.send(Msg::Stdin(c as char)).await.unwrap();
-                break;
-            }
+        if ch_buf_len == 0 && c == 3 {
+            msg_tx.send(Msg::Stdin(c as char)).await.unwrap();
+            break;
         }
         ch_buf[ch_buf_len] = c;
         ch_buf_len = (ch_buf_len + 1) % size_of::<char>();
         if let Ok(c) = std::str::from_utf8(&ch_buf) {
-            let c = if let Some(c) = c.chars().nth(0) { c } else { continue; };
+            let c = if let Some(c) = c.chars().next() { c } else { continue; };
             debug!("stdin char: {:?}", c);
             msg_tx.send(Msg::Stdin(c)).await.unwrap();
             ch_buf_len = 0;
@@ -163,11 +168,8 @@ async fn poll_user_input(cin_tx: &watch::Sender<String>, msg_tx: &mpsc::Sender<M
             match c {
                 '\x0D' | '\n' => {
                     let sin = str_buf.trim().to_string();
-                    if sin.len() != 0 {
-                        if sin.chars().nth(0).unwrap() == ':' {
-                            warn!("指令功能待开发，请稍后...");
-                        }
-                        else if let Err(e) = cin_tx.send(sin) {
+                    if !sin.is_empty() {
+                        if let Err(e) = cin_tx.send(sin) {
                             error!("cin tx send error!:{}", e);
                             break;
                         }
@@ -185,181 +187,120 @@ async fn poll_user_input(cin_tx: &watch::Sender<String>, msg_tx: &mpsc::Sender<M
     }
 }
 
-async fn init_room(mut server_stream: &mut TcpStream, user_info: &User,
-    cin_rx: &mut watch::Receiver<String>, msg_tx: &Sender<Msg>
-) {
-    // 接收服务端发送过来的所有房间内的peer
-    let clients: Vec<ClientInfo> = serde_json::from_slice(&net::read(&mut server_stream).await.unwrap()).unwrap();
-    info!("房间中共有{}个人", clients.len());
-    if clients.len() > 0 { info!("开始建立连接..."); }
-    let addr = server_stream.local_addr().unwrap();
-    let mut set = tokio::task::JoinSet::new();
-    for ci in clients {
-        let user_info = user_info.clone();
-        let cin_rx = cin_rx.clone();
-        let msg_tx = msg_tx.clone();
-        set.spawn(async move {
-            let mut stm = {
-                let sock = TcpSocket::new_v4().unwrap();
-                #[cfg(target_family = "unix")]
-                {sock.set_reuseport(true).unwrap();}
-                sock.set_reuseaddr(true).unwrap();
-                sock.bind(addr.clone()).unwrap();
-                if let Ok(stm) = sock.connect(ci.addr.clone()).await {
-                    stm
-                } else {
-                    warn!("连接{:?}失败", &ci);
-                    return Err(ci);
-                }
-            };
-            let other = {
-                if let Ok(client_info) = swap_info(&user_info, &mut stm, ci.addr).await {
-                    client_info
-                } else {
-                    warn!("连接{:?}失败，无法验证身份", &ci);
-                    return Err(ci);
-                }
-            };
-            info!("Connect: {:?}", &other);
-            tokio::spawn(Peer::new(
-                &other, stm, msg_tx, cin_rx
-            ).poll());
-            Ok(())
-        });
-    }
-    let mut err_cis: Vec<ClientInfo> = Vec::new();
-    while let Some(res) = set.join_next().await {
-        if let Ok(res) = res {
-            if let Err(ci) = res {
-                // 将未成功连接的回馈给服务端
-                err_cis.push(ci);
-            }
-        }
-    }
-    net::write(&mut server_stream, &serde_json::to_vec(&err_cis).unwrap()).await.unwrap();
-    info!("Connent Room Done.");
-}
-
-async fn handle_server(mut server_stream: TcpStream, user_info: User,
-        msg_tx: Sender<Msg>, clients: Arc<Mutex<Vec<PeerInfo>>>, cin_rx: watch::Receiver<String>,
-        mut sh_rx: tokio::sync::oneshot::Receiver<bool>
+/// 登录并加入房间后，把输入的内容交给会话，把收到的消息输出到终端
+async fn run(server_addr: String, lan_addr: Option<SocketAddr>, invite: Option<String>,
+    msg_tx: Sender<Msg>, mut cin_rx: watch::Receiver<String>, mut read_rx: Receiver<Vec<u64>>
 ) {
-    let addr = server_stream.local_addr().unwrap();
-    let mut reader = TryRead::new();
-    'a: loop {
+    let (session, mut events) = match start(&server_addr, lan_addr, invite, &msg_tx, &mut cin_rx).await {
+        Ok(res) => res,
+        Err(_) => return,
+    };
+    cin_rx.borrow_and_update();
+    loop {
         tokio::select! {
-            _ = sleep(Duration::from_millis(5000)) => {
-                debug!("server 发送心跳包");
-                net::write(&mut server_stream, "".as_bytes()).await.unwrap();
-            },
-            res = server_stream.readable() => {
-                if let Err(e) = res {
-                    error!("Fail to server_stream.readable() {}", e);
+            cres = cin_rx.changed() => {
+                if cres.is_err() {
                     break;
                 }
-                debug!("server readable");
-                let pkg = loop {
-                    match reader.poll(&mut server_stream) {
-                        Ok(_) => {
-                            break reader.package();
-                        },
+                let line = cin_rx.borrow_and_update().clone();
+                if line.starts_with('\x03') {
+                    break;
+                }
+                let res = if line.starts_with(':') {
+                    match Cmd::parse(&line) {
+                        Ok(cmd) => session.command(cmd).await,
                         Err(e) => {
-                            if let Some(e) = e.can_continue() {
-                                warn!("{:?}", e);
-                                break 'a;
-                            } else {
-                                continue 'a;
-                            }
+                            warn!("{}", e);
+                            continue;
                         },
                     }
+                } else {
+                    session.send(line).await
                 };
-                debug!("server read pkg done.");
-                if pkg.len() == 0 {
-                    // 心跳包，不用管
-                    debug!("from server 心跳包");
-                    continue;
+                if res.is_err() {
+                    warn!("会话已结束，按Ctrl+C退出");
                 }
-                if let Ok(ci) = serde_json::from_slice::<net::ClientInfo>(&pkg) {
-                    let theci = ClientInfo {
-                        id: ci.id,
-                        name: ci.name,
-                        addr: ci.addr.clone()
-                    };
-                    let sock = TcpSocket::new_v4().unwrap();
-                    #[cfg(target_family = "unix")]
-                    {sock.set_reuseport(true).unwrap();}
-                    sock.set_reuseaddr(true).unwrap();
-                    if let Err(e) = sock.bind(addr.clone()) {
-                        error!("Fail to bind {} {}", &addr, e);
+            },
+            ev = events.recv() => {
+                let msg = match ev {
+                    Some(Event::Message { msg, current: true }) => Msg::UserMsg(msg),
+                    Some(Event::History(msgs)) => Msg::History(msgs),
+                    Some(Event::Direct(msg)) => Msg::Direct(msg),
+                    Some(Event::Kicked(_)) => {
+                        warn!("已连接的成员仍然可以聊天，按Ctrl+C退出");
                         continue;
-                    };
-                    let mut sock = {
-                        match sock.connect(ci.addr.clone()).await {
-                            Ok(s) => s,
-                            Err(e) => {
-                                warn!("Fail to connent {:?} : {}", theci, e);
-                                continue;
-                            }
-                        }
-                    };
-                    if let Err(e) = swap_info(&user_info, &mut sock, ci.addr.clone()).await {
-                        warn!("无法获取客户端信息{:?}: {}", theci, e.to_string());
-                    }
-                    let prcs = Peer::new(&theci, sock, msg_tx.clone(), cin_rx.clone());
-                    let handle = tokio::spawn(prcs.poll());
-                    info!("Connect: {:?}", &theci);
-                    clients.lock().await.push(PeerInfo {
-                        ci: theci,
-                        handle
-                    });
-                } else {
-                    info!("Unknown Pakage {:?}", &pkg);
-                }
+                    },
+                    // 其他事件会话已经输出了日志
+                    Some(_) => continue,
+                    None => break,
+                };
+                msg_tx.send(msg).await.ok();
+            },
+            Some(ids) = read_rx.recv() => {
+                session.read(ids).await.ok();
             },
-            _ = &mut sh_rx => {
-                break;
-            }
         }
     }
-    info!("Server disconnent.");
+    session.close().await;
 }
 
-/// 交换相互的信息
-async fn swap_info(user_info: &User, mut sock: &mut TcpStream, addr: SocketAddr) -> Result<ClientInfo> {
-    // 将自己的信息发送到连接的客户端
-    let bui = net::BaseUserInfo {
-        id: user_info.id,
-        name: user_info.name.clone(),
-    };
-    net::write(&mut sock, &serde_json::to_vec(&bui).unwrap()).await?;
-    // 接收传过来的信息
-    let other = {
-        let bui = serde_json::from_slice::<net::BaseUserInfo>(
-                &match net::read(&mut sock).await {
-                    Ok(pkg) => { pkg },
-                    Err(e) => { return match e {
-                        net::ErrorType::IO(e) => { Err(e) }
-                        _ => { Err(std::io::ErrorKind::Other.into()) },
-                    }; },
-                })?;
-        ClientInfo {
-            id: bui.id,
-            name: bui.name,
-            addr: addr,
+/// 输入用户名和密码登录，再输入房间名和密码加入房间，也可以输入`:join <邀请码>`
+/// 局域网模式下只需要输入用户名、房间名和房间密码（可以为空）
+async fn start(server_addr: &str, lan_addr: Option<SocketAddr>, mut invite: Option<String>,
+    msg_tx: &Sender<Msg>, cin_rx: &mut watch::Receiver<String>
+) -> Result<(ChatSession, Receiver<Event>)> {
+    let mut cin = Cin {msg_tx, cin_rx};
+    if let Some(target) = lan_addr {
+        let name = cin.get("请输入用户名：").await?;
+        let room = cin.get("请输入房间名：").await?;
+        let passwd = cin.get("请输入房间密码（没有时直接回车）：").await?;
+        return ChatSession::lan(target, &name, &room, &passwd).await
+            .inspect_err(|e| error!("无法开启局域网模式：{}", e));
+    }
+    let (session, events) = loop {
+        let user = User {
+            id: 0,
+            name: cin.get("请输入用户名：").await?,
+            passwd: cin.get("请输入密码：").await?.into(),
+        };
+        match ChatSession::login(server_addr, &user).await {
+            Ok(res) => break res,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => warn!("{}", e),
+            Err(e) => {
+                error!("{}", e);
+                return Err(e);
+            },
         }
     };
-    // 这里就可以对传过来的信息和服务端的信息进行比对
-    // TODO
-    Ok(other)
+    loop {
+        let mut room = Room::default();
+        // 先使用命令行指定的邀请码
+        if let Some(token) = invite.take() {
+            room.invite = token.into();
+        } else {
+            room.name = cin.get("请输入房间名：").await?;
+            match room.name.strip_prefix(":join ") {
+                Some(token) => room.invite = token.trim().into(),
+                None => room.passwd = cin.get("请输入密码：").await?.into(),
+            }
+        }
+        match session.join(room).await {
+            Ok(_) => break,
+            Err(e) => warn!("{}", e),
+        }
+    }
+    Ok((session, events))
 }
 
 /// 当所有msg tx (Sender)关闭后才会退出
-async fn msg_handle(mut msg_rx: Receiver<Msg>) {
+async fn msg_handle(mut msg_rx: Receiver<Msg>, read_tx: Sender<Vec<u64>>) {
     let mut in_buf = String::new();
     let mut other_buf = String::new();
+    // 已显示但用户还没有回应过的消息
+    let mut unread: Vec<u64> = Vec::new();
     loop {
         let res = msg_rx.recv().await;
-        if let None = res {
+        if res.is_none() {
             break;
         }
         let msg = res.unwrap();
@@ -368,23 +309,37 @@ async fn msg_handle(mut msg_rx: Receiver<Msg>) {
                 print!("\x1B[1G\x1B[2K{}", log);
                 print!("{}{}", other_buf, in_buf);
             },
+            Msg::History(msgs) => {
+                for msg in msgs.iter() {
+                    print_chat_msg(msg, false);
+                }
+                print!("{}{}", other_buf, in_buf);
+            },
             Msg::UserMsg(msg) => {
-                print!("\x1B[1G\x1B[2K[{}] {}: {}\n",
-                        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"), &msg.0.name, &msg.1);
+                print_chat_msg(&msg, false);
+                unread.push(msg.id);
+                print!("{}{}", other_buf, in_buf);
+            },
+            Msg::Direct(msg) => {
+                print_chat_msg(&msg, true);
                 print!("{}{}", other_buf, in_buf);
             },
             Msg::Stdin(ch) => {
                 match ch {
                     '\x0D' | '\n' => {
+                        // 用户按下回车，认为之前显示的消息都已读
+                        if !unread.is_empty() {
+                            read_tx.try_send(std::mem::take(&mut unread)).ok();
+                        }
                         // 回车、换行
-                        if in_buf.len() != 0 {
+                        if !in_buf.is_empty() {
                             in_buf.clear();
                             other_buf.clear();
                             println!();
                         }
                     },
                     '\x08' | '\x7F' => {
-                        if let Some(_) = in_buf.pop() {
+                        if in_buf.pop().is_some() {
                             print!("\x1B[1G\x1B[2K{}{}", other_buf, in_buf);
                         }
                     },
@@ -396,7 +351,7 @@ async fn msg_handle(mut msg_rx: Receiver<Msg>) {
             },
             Msg::Other(str) => {
                 other_buf.push_str(&str);
-                other_buf = other_buf.split('\n').last().unwrap().to_string();
+                other_buf = other_buf.split('\n').next_back().unwrap().to_string();
                 print!("\x1B[1G\x1B[2K{}{}", other_buf, in_buf);
             },
         }
@@ -404,6 +359,21 @@ async fn msg_handle(mut msg_rx: Receiver<Msg>) {
     }
 }
 
+/// 输出一条聊天消息，时间使用发送方的时间戳
+fn print_chat_msg(msg: &ChatMsg, direct: bool) {
+    let time = match chrono::DateTime::from_timestamp_millis(msg.time) {
+        Some(t) => t.with_timezone(&chrono::Local),
+        None => chrono::Local::now(),
+    };
+    let reply = match msg.reply_to {
+        Some(id) => format!(" 回复#{}", net::id_tag(id)),
+        None => String::new(),
+    };
+    let direct = if direct { "（私信）" } else { "" };
+    println!("\x1B[1G\x1B[2K[{}] {}{} #{}{}: {}",
+            time.format("%Y-%m-%d %H:%M:%S"), &msg.sender.name, direct, msg.tag(), reply, &msg.body);
+}
+
 /// 这是一个日志的中转task
 /// 用于将env_logger的日志转发到msg handle
 async fn log_handle(mut log_rx: Receiver<String>, msg_tx: Sender<Msg>) {
@@ -416,137 +386,18 @@ async fn log_handle(mut log_rx: Receiver<String>, msg_tx: Sender<Msg>) {
 
 /// 当有内容要输出到stdout时, 使用这个枚举进行传递消息
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Msg {
-    UserMsg((BaseUserInfo, String)),
+    UserMsg(ChatMsg),
+    Direct(ChatMsg),
+    // 从其他客户端同步过来的聊天记录
+    History(Vec<ChatMsg>),
     Log(String),
     Stdin(char),
     // 通常用于不换行输出内容时
     Other(String),
 }
 
-struct PeerInfo {
-    ci: ClientInfo,
-    handle: tokio::task::JoinHandle<()>,
-}
-
-struct Peer {
-    ci: ClientInfo,
-    sock: TcpStream,
-    msg_tx: Sender<Msg>,
-    cin_rx: watch::Receiver<String>,
-}
-
-impl Peer {
-    fn new(ci: &ClientInfo, sock: TcpStream, msg_tx: Sender<Msg>, cin_rx: watch::Receiver<String>) -> Self {
-        Self {
-            ci: ClientInfo {
-                id: ci.id,
-                name: ci.name.clone(),
-                addr: ci.addr.clone(),
-            }, sock, msg_tx, cin_rx
-        }
-    }
-
-    async fn poll(mut self) {
-        let bui = BaseUserInfo{ id: self.ci.id, name: self.ci.name.clone() };
-        let mut reader = TryRead::new();
-        'a: loop {
-            tokio::select! {
-                rres = self.sock.readable() => {
-                    if let Err(_) = rres {
-                        return ;
-                    }
-                    loop {
-                        match reader.poll(&mut self.sock) {
-                            Ok(_) => {
-                                let pkg = reader.package();
-                                let msg = String::from_utf8(pkg);
-                                if let Ok(msg) = msg {
-                                    // 判断是否为心跳包，是就不用管
-                                    if msg.len() == 0 { continue; }
-                                    self.msg_tx.send(Msg::UserMsg((bui.clone(), msg))).await.unwrap();
-                                }
-                            },
-                            Err(e) => {
-                                if let Some(e) = e.can_continue() {
-                                    warn!("{:?}", e);
-                                    break 'a;
-                                } else {
-                                    break;
-                                }
-                            },
-                        }
-                    }
-                },
-                cres = self.cin_rx.changed() => {
-                    if let Err(_) = cres {
-                        break;
-                    }
-                    let msg = self.cin_rx.borrow_and_update().clone();
-                    if let Some(ch) = msg.chars().nth(0) {
-                        if ch == (3 as char) {
-                            break;
-                        }
-                    }
-                    net::write(&mut self.sock, msg.as_bytes()).await.unwrap();
-                },
-                // 每隔一段时间确认一次客户端是否存在
-                _ = sleep(Duration::from_secs(1 * 60)) => {
-                    if let Err(_) = net::write(&mut self.sock, "".as_bytes()).await {
-                        break;
-                    };
-                },
-            };
-        }
-        info!("Disconnect: {:?}", bui);
-        drop(self.msg_tx);
-    }
-}
-
-/// return 
-async fn login(mut serv: &mut TcpStream, msg_tx: &mpsc::Sender<Msg>, cin_rx: &mut watch::Receiver<String>) -> Result<User> {
-    let mut cin = Cin {msg_tx, cin_rx};
-    loop {
-        let mut u = User {
-            id: 0,
-            name: cin.get("请输入用户名：").await?,
-            passwd: cin.get("请输入密码：").await?,
-        };
-        net::write(&mut serv, u.package().unwrap().as_slice()).await.unwrap();
-        let stat = net::read(&mut serv).await.unwrap();
-        let stat = std::str::from_utf8(&stat).unwrap();
-        if stat.contains("OK") {
-            let base_info: net::BaseUserInfo = {
-                let pkg = net::read(serv).await.unwrap();
-                if let Ok(user_info) = serde_json::from_slice(&pkg) {
-                    user_info
-                } else {
-                    continue;
-                }
-            };
-            u.id = base_info.id;
-            info!("登录成功, ID: {}", u.id);
-            break Ok(u);
-        }
-        warn!("请输入正确的用户！");
-    }
-}
-
-async fn join_room(serv: &mut TcpStream, msg_tx: &mpsc::Sender<Msg>, cin_rx: &mut watch::Receiver<String>) -> Result<Room> {
-    let mut rom = Room { ..Default::default() };
-    let mut cin = Cin {msg_tx, cin_rx};
-    loop {
-        rom.name = cin.get("请输入房间名：").await?;
-        rom.passwd = cin.get("请输入密码：").await?;
-        net::write(serv, rom.package().unwrap().as_slice()).await.unwrap();
-        let buf = String::from_utf8(net::read(serv).await.unwrap()).unwrap();
-        if buf.to_uppercase().contains("OK") {
-            rom = net::read(serv).await.unwrap().into();
-            return Ok(rom);
-        }
-        warn!("请确认房间信息是否正确！");
-    }
-}
 
 struct Cin<'a> {
     msg_tx: &'a mpsc::Sender<Msg>,
@@ -564,6 +415,7 @@ impl Cin<'_> {
         }
         Ok(self.cin_rx.borrow_and_update().clone())
     }
+
 }
 
 struct MyLogTarget {
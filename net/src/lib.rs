@@ -1,12 +1,31 @@
+pub mod message;
+pub mod mux;
 pub mod package;
 pub mod room;
+pub mod server;
 
 pub type ID = u32;
 
+pub use message::*;
 pub use package::*;
 pub use room::*;
+pub use server::*;
 use std::net::SocketAddr;
 
+/// 解析`30`、`90s`、`30m`、`2h`、`7d`这样的时长，不带单位时为秒
+pub fn parse_duration(s: &str) -> Option<std::time::Duration> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse().ok()?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    num.checked_mul(secs).map(std::time::Duration::from_secs)
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 #[derive(Debug, Clone)]
 pub struct BaseUserInfo {
@@ -23,6 +42,44 @@ pub struct ClientInfo {
     pub addr: SocketAddr,
 }
 
+/// 密码、token等不能出现在日志里的字符串，Debug输出时隐藏内容
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Self(s.into())
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            f.write_str("\"\"")
+        } else {
+            f.write_str("\"***\"")
+        }
+    }
+}
+
 pub trait ToPackage {
     fn package(&self) -> Result<Vec<u8>, serde_json::Error>;
 }
@@ -32,7 +89,7 @@ pub trait ToPackage {
 pub struct User {
     pub id: ID,
     pub name: String,
-    pub passwd: String,
+    pub passwd: Secret,
 }
 
 impl User {
@@ -46,3 +103,23 @@ impl ToPackage for User {
         serde_json::to_vec(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        for s in ["", "m", "1w", "-1", "1.5h", "30 m", "99999999999999999999"] {
+            assert_eq!(parse_duration(s), None, "{}", s);
+        }
+        // 溢出时返回None
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / 60)), None);
+    }
+}
@@ -0,0 +1,162 @@
+use super::*;
+
+/// 一条聊天消息
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct ChatMsg {
+    /// 消息ID，由发送方生成
+    pub id: u64,
+    pub sender: BaseUserInfo,
+    /// 发送方的时间戳（毫秒）
+    pub time: i64,
+    pub room: ID,
+    pub body: String,
+    /// 回复的消息ID
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+}
+
+impl ChatMsg {
+    /// 消息ID的简写，用于显示和引用
+    pub fn tag(&self) -> String {
+        id_tag(self.id)
+    }
+}
+
+pub fn id_tag(id: u64) -> String {
+    format!("{:06x}", id & 0xff_ffff)
+}
+
+/// 发送文件前先告知对方文件的信息
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct FileOffer {
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+    /// 文件内容的SHA-256，十六进制
+    pub sha256: String,
+}
+
+/// 客户端之间传输的数据包
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub enum PeerPkg {
+    Chat(ChatMsg),
+    /// 房间内有成员连接不上时由其他成员转发的聊天消息，
+    /// seen为已经发给过的成员，ttl为还可以转发的次数
+    Relay {
+        msg: ChatMsg,
+        ttl: u8,
+        seen: Vec<ID>,
+        /// 这一次转发发给哪些peer，只在本地使用
+        #[serde(skip)]
+        to: Vec<ID>,
+    },
+    /// 只发给对方一个人的私信，不属于任何房间，也不会被转发
+    Direct(ChatMsg),
+    /// 请求对方某个房间最近的聊天记录
+    HistoryReq { room: ID, limit: usize },
+    History(Vec<ChatMsg>),
+    /// 送达回执
+    Ack(u64),
+    /// 已读回执
+    Read(Vec<u64>),
+    FileOffer(FileOffer),
+    /// 接收文件，offset为已经收到的字节数，用于断点续传
+    FileAccept { id: u64, offset: u64 },
+    FileDecline(u64),
+    FileChunk {
+        id: u64,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// 接收方校验文件后的结果
+    FileResult { id: u64, ok: bool },
+    /// 任何一方读写文件失败，取消这个文件的传输，连接和其他传输不受影响
+    FileAbort { id: u64, reason: String },
+}
+
+impl PeerPkg {
+    /// 数据包使用的逻辑通道
+    pub fn channel(&self) -> mux::ChannelId {
+        match self {
+            Self::Chat(_) | Self::Relay { .. } | Self::Direct(_)
+                | Self::Ack(_) | Self::Read(_) => mux::channel::CHAT,
+            Self::HistoryReq { .. } | Self::History(_) => mux::channel::HISTORY,
+            Self::FileChunk { .. } => mux::channel::FILE,
+            Self::FileOffer(_) | Self::FileAccept { .. }
+                | Self::FileDecline(_) | Self::FileResult { .. } | Self::FileAbort { .. } => mux::channel::CONTROL,
+        }
+    }
+
+    pub fn from(package: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice::<PeerPkg>(package)
+    }
+}
+
+impl ToPackage for PeerPkg {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 局域网模式下通过UDP广播的通告，同一个房间的成员收到后直接连接addr
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct LanAnnounce {
+    pub room: String,
+    pub user: BaseUserInfo,
+    /// 监听的地址，IP为0.0.0.0时使用通告的来源IP
+    pub addr: SocketAddr,
+}
+
+impl ToPackage for LanAnnounce {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 局域网模式下交换用户信息后双方发送，确认在同一个房间
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct LanHello {
+    pub room: String,
+    /// 对方用它证明自己知道房间密码
+    pub nonce: u64,
+}
+
+impl ToPackage for LanHello {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 房间密码、房间名和对方的nonce的SHA-256，密码本身不经过网络
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct LanProof {
+    pub proof: String,
+}
+
+impl ToPackage for LanProof {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 二进制数据使用base64编码，避免JSON数组带来的膨胀
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        STANDARD.decode(s).map_err(serde::de::Error::custom)
+    }
+}
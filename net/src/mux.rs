@@ -0,0 +1,299 @@
+//! 在一条TCP连接上复用多个逻辑通道
+//!
+//! 每条消息被拆成若干分片，每个分片是一个普通的数据包，
+//! 数据包内容为 `channel: u8, last: u8, payload: [u8]`。
+//! 发送时轮流从每个有数据的通道取一个分片，大的数据不会阻塞其他通道。
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Result},
+    net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    time::sleep,
+};
+use super::*;
+
+pub type ChannelId = u8;
+
+/// 客户端之间使用的逻辑通道
+pub mod channel {
+    use super::ChannelId;
+
+    /// 文件请求、回复等控制消息
+    pub const CONTROL: ChannelId = 0;
+    pub const CHAT: ChannelId = 1;
+    pub const HISTORY: ChannelId = 2;
+    pub const FILE: ChannelId = 3;
+}
+
+// 每个分片最多携带的数据
+const FRAGMENT_SIZE: usize = 4 * 1024;
+// 每个通道最多排队的消息数
+const CHANNEL_QUEUE: usize = 8;
+// 重组后的消息最大长度
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+// 一段时间没有数据发送时发送心跳包
+const HEARTBEAT: Duration = Duration::from_secs(60);
+
+struct Frame {
+    channel: ChannelId,
+    data: Vec<u8>,
+    // 消息发送完后释放，让同一通道的下一条消息可以排队
+    _permit: OwnedSemaphorePermit,
+}
+
+struct Inner {
+    tx: mpsc::UnboundedSender<Frame>,
+    queues: Mutex<HashMap<ChannelId, Arc<Semaphore>>>,
+}
+
+/// 向连接发送数据，可以clone给多个task使用
+#[derive(Clone)]
+pub struct MuxSender {
+    inner: Arc<Inner>,
+}
+
+/// 预留的发送位置
+pub struct MuxPermit {
+    inner: Arc<Inner>,
+    channel: ChannelId,
+    permit: OwnedSemaphorePermit,
+}
+
+impl MuxPermit {
+    pub fn send(self, data: Vec<u8>) -> Result<()> {
+        self.inner.tx.send(Frame { channel: self.channel, data, _permit: self.permit })
+            .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl MuxSender {
+    /// 等待通道有空位，可以在select!中使用
+    pub async fn reserve(&self, channel: ChannelId) -> Result<MuxPermit> {
+        let sem = self.inner.queues.lock().unwrap()
+            .entry(channel)
+            .or_insert_with(|| Arc::new(Semaphore::new(CHANNEL_QUEUE)))
+            .clone();
+        let permit = sem.acquire_owned().await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(MuxPermit { inner: self.inner.clone(), channel, permit })
+    }
+
+    pub async fn send(&self, channel: ChannelId, data: Vec<u8>) -> Result<()> {
+        self.reserve(channel).await?.send(data)
+    }
+}
+
+/// 接管一条连接，返回发送端和接收到的(通道, 消息)
+/// 连接断开后接收端返回None
+pub fn split(stm: TcpStream) -> (MuxSender, mpsc::Receiver<(ChannelId, Vec<u8>)>) {
+    let (rd, wr) = stm.into_split();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (in_tx, in_rx) = mpsc::channel(64);
+    tokio::spawn(write_loop(wr, rx));
+    tokio::spawn(read_loop(rd, in_tx));
+    let sender = MuxSender {
+        inner: Arc::new(Inner { tx, queues: Mutex::new(HashMap::new()) }),
+    };
+    (sender, in_rx)
+}
+
+/// 等待发送的消息，以及每条消息已经发送到的位置
+#[derive(Default)]
+struct Queues {
+    by_channel: HashMap<ChannelId, VecDeque<(Frame, usize)>>,
+    // 有数据等待发送的通道，按顺序轮流发送
+    order: VecDeque<ChannelId>,
+}
+
+impl Queues {
+    fn push(&mut self, frame: Frame) {
+        let q = self.by_channel.entry(frame.channel).or_default();
+        if q.is_empty() {
+            self.order.push_back(frame.channel);
+        }
+        q.push_back((frame, 0));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// 取出下一个要发送的分片
+    fn next_fragment(&mut self) -> Option<Vec<u8>> {
+        let ch = self.order.pop_front()?;
+        let q = self.by_channel.get_mut(&ch)?;
+        let (frame, pos) = q.front_mut()?;
+        let end = (*pos + FRAGMENT_SIZE).min(frame.data.len());
+        let last = end == frame.data.len();
+        let mut buf = Vec::with_capacity(2 + end - *pos);
+        buf.push(ch);
+        buf.push(last as u8);
+        buf.extend_from_slice(&frame.data[*pos..end]);
+        *pos = end;
+        if last {
+            q.pop_front();
+        }
+        if !q.is_empty() {
+            self.order.push_back(ch);
+        }
+        Some(buf)
+    }
+}
+
+async fn write_loop(mut wr: OwnedWriteHalf, mut rx: mpsc::UnboundedReceiver<Frame>) {
+    let mut queues = Queues::default();
+    loop {
+        // 没有数据要发送时等待新的消息，空闲太久就发送心跳包
+        if queues.is_empty() {
+            tokio::select! {
+                frame = rx.recv() => {
+                    match frame {
+                        Some(frame) => queues.push(frame),
+                        None => break,
+                    }
+                },
+                _ = sleep(HEARTBEAT) => {
+                    if write(&mut wr, &[]).await.is_err() {
+                        break;
+                    }
+                    continue;
+                },
+            }
+        }
+        while let Ok(frame) = rx.try_recv() {
+            queues.push(frame);
+        }
+        let buf = match queues.next_fragment() {
+            Some(buf) => buf,
+            None => continue,
+        };
+        if let Err(e) = write(&mut wr, &buf).await {
+            debug!("mux write: {}", e);
+            break;
+        }
+    }
+    wr.shutdown().await.ok();
+}
+
+async fn read_loop(mut rd: OwnedReadHalf, tx: mpsc::Sender<(ChannelId, Vec<u8>)>) {
+    // 未接收完的消息
+    let mut partial: HashMap<ChannelId, Vec<u8>> = HashMap::new();
+    loop {
+        let mut head = [0u8; 8];
+        if rd.read_exact(&mut head).await.is_err() {
+            break;
+        }
+        let len = match verify_head(&head) {
+            Some(len) if len as usize <= FRAGMENT_SIZE + 2 => len as usize,
+            _ => {
+                warn!("mux: {:?}", ErrorType::NotPakage(head.to_vec()));
+                break;
+            },
+        };
+        let mut data = vec![0u8; len];
+        if rd.read_exact(&mut data).await.is_err() {
+            break;
+        }
+        // 心跳包
+        if data.len() < 2 {
+            continue;
+        }
+        let (ch, last) = (data[0], data[1] != 0);
+        let buf = partial.entry(ch).or_default();
+        buf.extend_from_slice(&data[2..]);
+        if buf.len() > MAX_MESSAGE {
+            warn!("mux: message on channel {} too large", ch);
+            break;
+        }
+        if last {
+            let msg = partial.remove(&ch).unwrap_or_default();
+            if tx.send((ch, msg)).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn frame(channel: ChannelId, len: usize) -> Frame {
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        Frame { channel, data: vec![channel; len], _permit: permit }
+    }
+
+    #[test]
+    fn fragments_take_turns() {
+        let mut queues = Queues::default();
+        queues.push(frame(channel::FILE, FRAGMENT_SIZE * 2 + 1));
+        queues.push(frame(channel::CHAT, 10));
+        queues.push(frame(channel::CHAT, 0));
+        let mut sent = Vec::new();
+        while let Some(buf) = queues.next_fragment() {
+            sent.push((buf[0], buf[1], buf.len() - 2));
+        }
+        // 大的文件不会让聊天消息等到发完，空消息也有一个分片
+        assert_eq!(sent, [
+            (channel::FILE, 0, FRAGMENT_SIZE),
+            (channel::CHAT, 1, 10),
+            (channel::FILE, 0, FRAGMENT_SIZE),
+            (channel::CHAT, 1, 0),
+            (channel::FILE, 1, 1),
+        ]);
+        assert!(queues.is_empty());
+    }
+
+    async fn pair() -> ((MuxSender, mpsc::Receiver<(ChannelId, Vec<u8>)>), (MuxSender, mpsc::Receiver<(ChannelId, Vec<u8>)>)) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (split(client), split(server))
+    }
+
+    #[tokio::test]
+    async fn reassemble() {
+        let ((tx, _), (_, mut rx)) = pair().await;
+        let big: Vec<u8> = (0..FRAGMENT_SIZE * 5 + 7).map(|i| i as u8).collect();
+        tx.send(channel::FILE, big.clone()).await.unwrap();
+        tx.send(channel::CHAT, b"hi".to_vec()).await.unwrap();
+        tx.send(channel::CONTROL, Vec::new()).await.unwrap();
+        let mut got = HashMap::new();
+        for _ in 0..3 {
+            let (ch, msg) = rx.recv().await.unwrap();
+            got.insert(ch, msg);
+        }
+        assert_eq!(got[&channel::FILE], big);
+        assert_eq!(got[&channel::CHAT], b"hi");
+        assert!(got[&channel::CONTROL].is_empty());
+    }
+
+    #[tokio::test]
+    async fn chat_not_blocked_by_file() {
+        let ((tx, _), (_, mut rx)) = pair().await;
+        tx.send(channel::FILE, vec![0; FRAGMENT_SIZE * 64]).await.unwrap();
+        tx.send(channel::CHAT, b"hi".to_vec()).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), (channel::CHAT, b"hi".to_vec()));
+        assert_eq!(rx.recv().await.unwrap().0, channel::FILE);
+    }
+
+    #[tokio::test]
+    async fn oversized_fragment_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_tx, mut rx) = split(server);
+        let len = (FRAGMENT_SIZE + 3) as u32;
+        client.write_all(&len.to_be_bytes()).await.unwrap();
+        client.write_all(&(!len).to_be_bytes()).await.unwrap();
+        client.write_all(&vec![1; len as usize]).await.unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -26,7 +26,7 @@ impl ErrorType {
                 None
             },
             Self::MissingHead(vec) => {
-                if vec.len() == 0 {
+                if vec.is_empty() {
                     Some(Self::MissingHead(vec))
                 } else {
                     None
@@ -44,7 +44,7 @@ impl ErrorType {
     }
 }
 
-fn verify_head(buf: &[u8; 8]) -> Option<u32> {
+pub(crate) fn verify_head(buf: &[u8; 8]) -> Option<u32> {
     let mut u32_byte_buf: [u8; 4] = [0u8; 4];
     u32_byte_buf.copy_from_slice(&buf[..4]);
     let slen = u32::from_be_bytes(u32_byte_buf);
@@ -57,7 +57,7 @@ fn verify_head(buf: &[u8; 8]) -> Option<u32> {
     }
 }
 
-pub async fn read(stm: &mut TcpStream) -> result::Result<Vec<u8>, ErrorType> {
+pub async fn read<R: AsyncRead + Unpin>(stm: &mut R) -> result::Result<Vec<u8>, ErrorType> {
     let mut len_buf: [u8; 8] = [0; 8];
     match stm.read(&mut len_buf).await {
         Ok(siz) => {
@@ -72,9 +72,8 @@ pub async fn read(stm: &mut TcpStream) -> result::Result<Vec<u8>, ErrorType> {
     let len = if let Some(len) = verify_head(&len_buf) { len as usize }
                         else { return Err(ErrorType::NotPakage(Vec::from(len_buf))); };
     debug!("net::package::read begin len: {}", len);
-    let mut data: Vec<u8> = Vec::with_capacity(len);
-    unsafe { data.set_len(len); }
-    let rlen = match stm.read(&mut data).await {
+    let mut data: Vec<u8> = vec![0u8; len];
+    let rlen = match stm.read_exact(&mut data).await {
         Ok(len) => { len },
         Err(e) => {
             return Err(ErrorType::IO(e));
@@ -94,29 +93,35 @@ pub struct TryRead {
 
 #[derive(Debug)]
 enum TryReadBuf {
-    BUF(([u8; 8], usize)),
-    LEN(u32),
+    Buf(([u8; 8], usize)),
+    Len(u32),
 }
 
 impl TryReadBuf {
     fn len(&self) -> Option<u32> {
         match self {
-            Self::BUF(_) => None,
-            Self::LEN(l) => Some(*l),
+            Self::Buf(_) => None,
+            Self::Len(l) => Some(*l),
         }
     }
 }
 
+impl Default for TryRead {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TryRead {
     pub fn new() -> Self {
         Self {
             pkg: Vec::with_capacity(0),
-            len_buf: TryReadBuf::BUF(([0; 8], 0)),
+            len_buf: TryReadBuf::Buf(([0; 8], 0)),
         }
     }
 
     pub fn poll(&mut self, stm: &mut TcpStream) -> result::Result<u32, ErrorType> {
-        if let TryReadBuf::BUF((len_buf, buf_len)) = self.len_buf.borrow_mut() {
+        if let TryReadBuf::Buf((len_buf, buf_len)) = self.len_buf.borrow_mut() {
             let rlen = match stm.try_read(&mut len_buf[*buf_len..]) {
                 Ok(len) => { len },
                 Err(e) => { return Err(ErrorType::IO(e)); },
@@ -128,16 +133,16 @@ impl TryRead {
             if *buf_len < 8 {
                 return Err(ErrorType::None);
             }
-            let pkg_len = if let Some(len) = verify_head(&len_buf) { len }
+            let pkg_len = if let Some(len) = verify_head(len_buf) { len }
                             else {
                                 self.pkg = Vec::with_capacity(0);
                                 *buf_len = 0;
                                 return Err(ErrorType::NotPakage(Vec::from(len_buf)));
                             };
             self.pkg = Vec::with_capacity(pkg_len as usize);
-            self.len_buf = TryReadBuf::LEN(pkg_len);
+            self.len_buf = TryReadBuf::Len(pkg_len);
         }
-        let pkg_len = if let TryReadBuf::LEN(l) = self.len_buf { l } else { 0 };
+        let pkg_len = if let TryReadBuf::Len(l) = self.len_buf { l } else { 0 };
         if self.len_buf.len().unwrap() as usize == self.pkg.len() {
             return Ok(0);
         }
@@ -156,7 +161,7 @@ impl TryRead {
         if self.pkg.len() == pkg_len as usize {
             return Ok(rlen as u32);
         }
-        return Err(ErrorType::None);
+        Err(ErrorType::None)
     }
 
     pub fn status(&self)  {
@@ -165,21 +170,21 @@ impl TryRead {
 
     pub fn clear(&mut self) {
         self.pkg = Vec::with_capacity(0);
-        self.len_buf = TryReadBuf::BUF(([0; 8], 0));
+        self.len_buf = TryReadBuf::Buf(([0; 8], 0));
     }
 
     pub fn package(&mut self) -> Vec<u8> {
-        self.len_buf = TryReadBuf::BUF(([0; 8], 0));
+        self.len_buf = TryReadBuf::Buf(([0; 8], 0));
         std::mem::take(&mut self.pkg)
     }
 }
 
-pub async fn write(stm: &mut TcpStream, data: &[u8]) -> Result<()> {
+pub async fn write<W: AsyncWrite + Unpin>(stm: &mut W, data: &[u8]) -> Result<()> {
     let slen = data.len() as u32;
     let mut len_buf = [0u8; 8];
     len_buf[..4].copy_from_slice(&slen.to_be_bytes());
     len_buf[4..].copy_from_slice(&(!slen).to_be_bytes());
     stm.write_all(&len_buf).await?;
-    stm.write_all(&data).await?;
+    stm.write_all(data).await?;
     Ok(())
 }
@@ -1,11 +1,27 @@
 use super::*;
 
 #[derive(serde::Serialize, serde::Deserialize)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Room {
     pub id: u32,
     pub name: String,
-    pub passwd: String,
+    /// 服务端不会把密码发回给客户端
+    pub passwd: Secret,
+    /// 房间话题
+    #[serde(default)]
+    pub topic: String,
+    /// 房间介绍
+    #[serde(default)]
+    pub description: String,
+    /// 最多容纳的人数，0为不限
+    #[serde(default)]
+    pub capacity: u32,
+    /// 常驻房间没有成员时也不会被删除，服务器重启后恢复
+    #[serde(default)]
+    pub persistent: bool,
+    /// 加入房间时使用的邀请码，有邀请码时不需要房间名和密码
+    #[serde(default, skip_serializing_if = "Secret::is_empty")]
+    pub invite: Secret,
 }
 
 impl Room {
@@ -35,3 +51,58 @@ impl From<Vec<u8>> for Room {
         serde_json::from_slice(pkg.as_slice()).unwrap()
     }
 }
+
+/// 房主和管理员管理房间的指令，发给服务端执行
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct RoomCmd {
+    pub room: ID,
+    pub op: RoomOp,
+}
+
+/// 对房间的操作，user为目标成员的用户名
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub enum RoomOp {
+    /// 将成员移出房间
+    Kick { user: String, reason: String },
+    /// 移出房间并禁止再次加入
+    Ban { user: String },
+    Unban { user: String },
+    /// 修改房间密码，只有房主可以
+    Passwd(Secret),
+    /// 设为管理员，只有房主可以
+    Op { user: String },
+    /// 取消管理员，只有房主可以
+    Deop { user: String },
+    /// 转让房主，原房主成为管理员
+    Transfer { user: String },
+    /// 修改话题，房主和管理员可以
+    Topic(String),
+    /// 修改房间介绍，只有房主可以
+    Describe(String),
+    /// 修改人数上限，0为不限，只有房主可以
+    Capacity(u32),
+    /// 生成邀请码，ttl秒后过期，once为只能使用一次，只有房主可以
+    Invite { ttl: u64, once: bool },
+}
+
+impl ToPackage for RoomCmd {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 离开房间，不断开与服务端的连接
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct LeaveRoom {
+    /// 要离开的房间ID
+    pub leave: ID,
+}
+
+impl ToPackage for LeaveRoom {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
@@ -0,0 +1,97 @@
+use super::*;
+
+/// 登录成功后服务端返回的会话信息
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub id: ID,
+    pub name: String,
+    /// 与服务端断开后用来恢复会话
+    #[serde(default)]
+    pub token: Secret,
+}
+
+/// 断线重连时代替User发送，恢复之前的会话
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct Resume {
+    pub token: Secret,
+}
+
+/// 与房间内某个成员的连接意外断开后，请求服务端协调双方重新连接
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct Redial {
+    /// 断开的成员ID
+    pub redial: ID,
+}
+
+impl ToPackage for Redial {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 按服务端安排的时间连接某个成员后，报告是否连接成功
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub struct PunchReport {
+    pub peer: ID,
+    pub ok: bool,
+}
+
+impl ToPackage for PunchReport {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 服务端主动发给客户端的数据包
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone)]
+pub enum ServerPkg {
+    /// 服务器公告
+    Notice(String),
+    /// 被管理员踢出，附带原因
+    Kicked(String),
+    /// 所在的房间被管理员关闭
+    RoomClosed(ID),
+    /// 服务器即将关闭，reconnect为可以重新连接的地址
+    Shutdown { reconnect: Option<String> },
+    /// 有新成员加入房间，或者恢复会话后需要连接的成员，随后会收到连接这个成员的Punch
+    PeerJoined { room: ID, peer: ClientInfo },
+    /// 和peer建立连接：双方都在delay毫秒后连接对方，失败时每隔interval毫秒再试一次，最多attempts次，
+    /// 双方按同一个时间表连接，同时打开更容易成功。时间是相对收到时的，不受两边时钟不一致的影响，
+    /// 但两边收到Punch的时间会相差它们到服务端的延迟之差，这个差值没有抵消
+    Punch { peer: ClientInfo, delay: u64, interval: u64, attempts: u32 },
+    /// 房间内有成员离开，reason为离开的原因
+    PeerLeft { room: ID, user: BaseUserInfo, reason: String },
+    /// 房间内的通知，例如成员的角色变化
+    RoomNotice { room: ID, text: String },
+    /// 被房主或管理员移出房间
+    RemovedFromRoom { room: ID, reason: String },
+    /// RoomCmd的执行结果
+    CmdReply { ok: bool, text: String },
+}
+
+impl ServerPkg {
+    pub fn from(package: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice::<ServerPkg>(package)
+    }
+}
+
+impl ToPackage for ServerPkg {
+    fn package(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// 默认的管理socket路径，server-ctl通过它执行管理指令
+/// 优先放在只有当前用户能访问的$XDG_RUNTIME_DIR中
+#[cfg(unix)]
+pub fn admin_sock_path() -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => format!("{}/p2p-chat-server.sock", dir.trim_end_matches('/')),
+        _ => "/tmp/p2p-chat-server.sock".into(),
+    }
+}
@@ -0,0 +1,463 @@
+use std::{collections::HashMap, fmt::Write, net::IpAddr, sync::Arc, time::Duration};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex};
+use net::ID;
+use super::{moderation::Role, state::SavedBan, AllRoomInfo, AllUserInfo, Event};
+
+const HELP: &str = "\
+rooms                         查看所有房间
+users                         查看在线用户
+stats                         查看每个连接的流量统计
+kick <用户ID|用户名> [原因]     踢出用户
+ban <用户名|IP> [时长]          封禁用户名或IP，时长如30s、10m、2h、1d，不填为永久
+unban <用户名|IP>              解除封禁
+bans                          查看封禁列表
+close <房间ID|房间名>           关闭房间
+persist <房间ID|房间名> [on|off]  设置常驻房间，没有成员时保留，重启后恢复
+broadcast <房间ID|房间名|*> <内容>  向房间或所有人发送公告
+exit [重连地址]                 关闭服务器，可以告诉客户端去连接新的地址";
+
+/// 服务端的管理指令
+#[derive(Debug)]
+pub enum AdminCmd {
+    Help,
+    Rooms,
+    Users,
+    Stats,
+    Kick { user: String, reason: String },
+    Ban { target: BanTarget, time: Option<Duration> },
+    Unban(BanTarget),
+    Bans,
+    Close(String),
+    Persist { room: String, on: bool },
+    /// room为None时发给所有人
+    Broadcast { room: Option<String>, text: String },
+    /// 关闭服务器，reconnect为提示客户端重新连接的地址
+    Exit { reconnect: Option<String> },
+}
+
+impl AdminCmd {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match name.to_lowercase().as_str() {
+            "help" | "?" => Ok(Self::Help),
+            // 兼容原来的echo rooms和echo users
+            "echo" => match args.to_lowercase().as_str() {
+                "rooms" => Ok(Self::Rooms),
+                "users" => Ok(Self::Users),
+                _ => Err("用法：echo rooms|users".into()),
+            },
+            "rooms" => Ok(Self::Rooms),
+            "users" => Ok(Self::Users),
+            "stats" => Ok(Self::Stats),
+            "kick" => {
+                let (user, reason) = args.split_once(' ').unwrap_or((args, ""));
+                if user.is_empty() {
+                    return Err("用法：kick <用户ID|用户名> [原因]".into());
+                }
+                let reason = if reason.trim().is_empty() { "被管理员踢出" } else { reason.trim() };
+                Ok(Self::Kick { user: user.into(), reason: reason.into() })
+            },
+            "ban" => {
+                let mut it = args.split_whitespace();
+                let target = it.next().ok_or("用法：ban <用户名|IP> [时长]")?;
+                let time = match it.next() {
+                    Some(t) => Some(net::parse_duration(t).ok_or(format!("无法识别的时长：{}", t))?),
+                    None => None,
+                };
+                Ok(Self::Ban { target: BanTarget::parse(target), time })
+            },
+            "unban" => {
+                let target = args.split_whitespace().next().ok_or("用法：unban <用户名|IP>")?;
+                Ok(Self::Unban(BanTarget::parse(target)))
+            },
+            "bans" => Ok(Self::Bans),
+            "close" => {
+                if args.is_empty() {
+                    return Err("用法：close <房间ID|房间名>".into());
+                }
+                Ok(Self::Close(args.into()))
+            },
+            "persist" => {
+                let mut it = args.split_whitespace();
+                let room = it.next().ok_or("用法：persist <房间ID|房间名> [on|off]")?;
+                let on = match it.next().map(|a| a.to_lowercase()).as_deref() {
+                    None | Some("on") => true,
+                    Some("off") => false,
+                    Some(a) => return Err(format!("无法识别的参数：{}", a)),
+                };
+                Ok(Self::Persist { room: room.into(), on })
+            },
+            "broadcast" | "bc" => {
+                let (room, text) = args.split_once(' ')
+                    .ok_or("用法：broadcast <房间ID|房间名|*> <内容>")?;
+                let room = if room == "*" { None } else { Some(room.to_string()) };
+                Ok(Self::Broadcast { room, text: text.trim().into() })
+            },
+            "exit" => {
+                let reconnect = args.split_whitespace().next().map(|a| a.to_string());
+                Ok(Self::Exit { reconnect })
+            },
+            _ => Err(format!("未知指令：{}，输入help查看所有指令", name)),
+        }
+    }
+}
+
+/// 封禁的对象
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BanTarget {
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    /// 能解析为IP地址的当作IP，否则当作用户名
+    fn parse(s: &str) -> Self {
+        match s.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Name(s.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "用户名 {}", name),
+            Self::Ip(ip) => write!(f, "IP {}", ip),
+        }
+    }
+}
+
+/// 封禁列表，值为解封的时间，None为永久封禁
+#[derive(Debug, Default)]
+pub struct Bans {
+    list: HashMap<BanTarget, Option<DateTime<Local>>>,
+}
+
+impl Bans {
+    pub fn add(&mut self, target: BanTarget, time: Option<Duration>) -> Option<DateTime<Local>> {
+        // 时长超出能表示的范围时当作永久封禁
+        let until = time.and_then(|t| chrono::Duration::from_std(t).ok())
+            .and_then(|t| Local::now().checked_add_signed(t));
+        self.list.insert(target, until);
+        until
+    }
+
+    pub fn remove(&mut self, target: &BanTarget) -> bool {
+        self.list.remove(target).is_some()
+    }
+
+    /// 是否被封禁，顺便清除已经过期的
+    pub fn is_banned(&mut self, target: &BanTarget) -> bool {
+        self.clean();
+        self.list.contains_key(target)
+    }
+
+    fn clean(&mut self) {
+        let now = Local::now();
+        self.list.retain(|_, until| until.is_none_or(|t| t > now));
+    }
+
+    pub fn save(&mut self) -> Vec<SavedBan> {
+        self.clean();
+        self.list.iter()
+            .map(|(target, until)| SavedBan { target: target.clone(), until: until.map(|t| t.timestamp_millis()) })
+            .collect()
+    }
+
+    pub fn load(&mut self, bans: Vec<SavedBan>) {
+        for ban in bans {
+            let until = ban.until.and_then(DateTime::from_timestamp_millis).map(|t| t.with_timezone(&Local));
+            self.list.insert(ban.target, until);
+        }
+        self.clean();
+    }
+}
+
+/// 执行管理指令，指令的输出以字符串返回
+#[derive(Clone)]
+pub struct Admin {
+    pub rooms: Arc<Mutex<AllRoomInfo>>,
+    pub users: Arc<Mutex<AllUserInfo>>,
+    pub bans: Arc<Mutex<Bans>>,
+    /// 通知服务器关闭，附带重连地址
+    pub shutdown: mpsc::Sender<Option<String>>,
+}
+
+impl Admin {
+    pub async fn exec(&self, cmd: AdminCmd) -> String {
+        match cmd {
+            AdminCmd::Help => HELP.into(),
+            AdminCmd::Rooms => self.rooms().await,
+            AdminCmd::Users => self.users().await,
+            AdminCmd::Stats => self.stats().await,
+            AdminCmd::Kick { user, reason } => {
+                // 连接的事件队列可能是满的，放开锁再发送，不能让整个服务器等着
+                let (tx, out) = {
+                    let users = self.users.lock().await;
+                    let id = match find_user(&users, &user) {
+                        Some(id) => id,
+                        None => return format!("找不到用户：{}", user),
+                    };
+                    let conn = &users.conns[&id];
+                    (conn.tx.clone(), format!("已踢出用户[{}] {}（{}）", id, users.by_id[&id].name, conn.addr))
+                };
+                tx.send(Event::Kick(reason)).await.ok();
+                out
+            },
+            AdminCmd::Ban { target, time } => {
+                let until = self.bans.lock().await.add(target.clone(), time);
+                let mut out = match until {
+                    Some(t) => format!("已封禁{}，到{}解封", target, t.format("%Y-%m-%d %H:%M:%S")),
+                    None => format!("已永久封禁{}", target),
+                };
+                // 踢出已经在线的，放开锁之后再发送
+                let mut txs = Vec::new();
+                {
+                    let users = self.users.lock().await;
+                    for (id, conn) in users.conns.iter() {
+                        let hit = match &target {
+                            BanTarget::Name(name) => &users.by_id[id].name == name,
+                            BanTarget::Ip(ip) => conn.addr.ip() == *ip,
+                        };
+                        if hit {
+                            txs.push(conn.tx.clone());
+                            write!(out, "\n已踢出用户[{}] {}（{}）", id, users.by_id[id].name, conn.addr).ok();
+                        }
+                    }
+                }
+                for tx in txs {
+                    tx.send(Event::Kick("已被服务器封禁".into())).await.ok();
+                }
+                out
+            },
+            AdminCmd::Unban(target) => {
+                if self.bans.lock().await.remove(&target) {
+                    format!("已解除封禁{}", target)
+                } else {
+                    format!("{}没有被封禁", target)
+                }
+            },
+            AdminCmd::Bans => {
+                let mut bans = self.bans.lock().await;
+                bans.clean();
+                if bans.list.is_empty() {
+                    return "没有封禁".into();
+                }
+                let mut out = format!("共{}条封禁", bans.list.len());
+                for (target, until) in bans.list.iter() {
+                    let until = match until {
+                        Some(t) => format!("到{}", t.format("%Y-%m-%d %H:%M:%S")),
+                        None => "永久".into(),
+                    };
+                    write!(out, "\n  {}  {}", target, until).ok();
+                }
+                out
+            },
+            AdminCmd::Close(room) => {
+                let mut rooms = self.rooms.lock().await;
+                let rid = match find_room(&rooms, &room) {
+                    Some(rid) => rid,
+                    None => return format!("找不到房间：{}", room),
+                };
+                let room = rooms.remove(rid);
+                drop(rooms);
+                for c in room.cs.values() {
+                    c.tx.send(Event::RoomClosed(rid)).await.ok();
+                }
+                format!("已关闭房间[{}] {}，{}人被移出", rid, room.name, room.cs.len())
+            },
+            AdminCmd::Persist { room, on } => {
+                let mut rooms = self.rooms.lock().await;
+                let rid = match find_room(&rooms, &room) {
+                    Some(rid) => rid,
+                    None => return format!("找不到房间：{}", room),
+                };
+                let r = rooms.by_id.get_mut(&rid).unwrap();
+                r.persistent = on;
+                if on {
+                    format!("房间[{}] {}已设为常驻", rid, r.name)
+                } else if r.cs.is_empty() {
+                    let r = rooms.remove(rid);
+                    format!("房间[{}] {}不再常驻，已删除空房间", rid, r.name)
+                } else {
+                    format!("房间[{}] {}不再常驻，最后一个成员离开后删除", rid, r.name)
+                }
+            },
+            AdminCmd::Broadcast { room, text } => {
+                let txs = match room {
+                    Some(room) => {
+                        let rooms = self.rooms.lock().await;
+                        match find_room(&rooms, &room) {
+                            Some(rid) => rooms.by_id[&rid].cs.values().map(|c| c.tx.clone()).collect(),
+                            None => return format!("找不到房间：{}", room),
+                        }
+                    },
+                    None => {
+                        let users = self.users.lock().await;
+                        users.conns.values().map(|c| c.tx.clone()).collect::<Vec<_>>()
+                    },
+                };
+                for tx in txs.iter() {
+                    tx.send(Event::Notice(text.clone())).await.ok();
+                }
+                format!("公告已发送给{}人", txs.len())
+            },
+            AdminCmd::Exit { reconnect } => {
+                self.shutdown.send(reconnect).await.ok();
+                "正在关闭服务器".into()
+            },
+        }
+    }
+
+    async fn rooms(&self) -> String {
+        let rooms = self.rooms.lock().await;
+        let mut ids: Vec<&ID> = rooms.by_id.keys().collect();
+        ids.sort();
+        let mut out = format!("共{}个房间", ids.len());
+        for id in ids {
+            let room = &rooms.by_id[id];
+            let mut members: Vec<String> = room.cs.values()
+                .map(|c| match room.role(c.id) {
+                    Role::Owner => format!("{}({})[房主]", c.name, c.id),
+                    Role::Moderator => format!("{}({})[管理员]", c.name, c.id),
+                    Role::Member => format!("{}({})", c.name, c.id),
+                })
+                .collect();
+            members.sort();
+            let capacity = if room.capacity == 0 { String::new() } else { format!("/{}", room.capacity) };
+            write!(out, "\n  [{}] {}{}  {}{}人：{}", id, room.name, if room.persistent { "[常驻]" } else { "" },
+                    room.cs.len(), capacity, members.join(", ")).ok();
+            if !room.topic.is_empty() {
+                write!(out, "\n      话题：{}", room.topic).ok();
+            }
+        }
+        out
+    }
+
+    async fn users(&self) -> String {
+        let users = self.users.lock().await;
+        let rooms = self.rooms.lock().await;
+        let mut ids: Vec<&ID> = users.by_id.keys().collect();
+        ids.sort();
+        let mut out = format!("共{}个在线用户", ids.len());
+        for id in ids {
+            let in_rooms: Vec<&str> = rooms.by_id.values()
+                .filter(|r| r.cs.contains_key(id))
+                .map(|r| r.name.as_str())
+                .collect();
+            let addr = users.conns.get(id).map_or("等待重连".into(), |c| c.addr.to_string());
+            write!(out, "\n  [{}] {}  {}  房间：{}", id, users.by_id[id].name, addr,
+                    if in_rooms.is_empty() { "无".into() } else { in_rooms.join(", ") }).ok();
+        }
+        out
+    }
+
+    async fn stats(&self) -> String {
+        let users = self.users.lock().await;
+        let mut ids: Vec<&ID> = users.conns.keys().collect();
+        ids.sort();
+        let mut out = format!("共{}个连接", ids.len());
+        for id in ids {
+            let conn = &users.conns[id];
+            let (pkgs_in, bytes_in, pkgs_out, bytes_out) = conn.stats.get();
+            write!(out, "\n  [{}] {}  {}  在线{}  收到{}个包/{}字节  发出{}个包/{}字节",
+                    id, users.by_id[id].name, conn.addr, fmt_duration(conn.since.elapsed()),
+                    pkgs_in, bytes_in, pkgs_out, bytes_out).ok();
+        }
+        out
+    }
+}
+
+fn find_user(users: &AllUserInfo, s: &str) -> Option<ID> {
+    if let Ok(id) = s.parse::<ID>() {
+        if users.conns.contains_key(&id) {
+            return Some(id);
+        }
+    }
+    users.by_name.get(s).copied().filter(|id| users.conns.contains_key(id))
+}
+
+fn find_room(rooms: &AllRoomInfo, s: &str) -> Option<ID> {
+    if let Ok(id) = s.parse::<ID>() {
+        if rooms.by_id.contains_key(&id) {
+            return Some(id);
+        }
+    }
+    rooms.by_name.get(s).copied()
+}
+
+fn fmt_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 24 * 60 * 60 {
+        format!("{}天{}小时", secs / 86400, secs % 86400 / 3600)
+    } else if secs >= 60 * 60 {
+        format!("{}小时{}分", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}分{}秒", secs / 60, secs % 60)
+    } else {
+        format!("{}秒", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> AdminCmd {
+        AdminCmd::parse(line).unwrap()
+    }
+
+    #[test]
+    fn parse_cmds() {
+        assert!(matches!(parse("HELP"), AdminCmd::Help));
+        assert!(matches!(parse("echo rooms"), AdminCmd::Rooms));
+        assert!(matches!(parse("  users  "), AdminCmd::Users));
+        assert!(matches!(parse("kick bob"), AdminCmd::Kick { user, reason } if user == "bob" && reason == "被管理员踢出"));
+        assert!(matches!(parse("kick 3  spam  "), AdminCmd::Kick { user, reason } if user == "3" && reason == "spam"));
+        assert!(matches!(parse("ban 10.0.0.9"),
+                AdminCmd::Ban { target: BanTarget::Ip(ip), time: None } if ip.to_string() == "10.0.0.9"));
+        assert!(matches!(parse("ban mallory 2h"),
+                AdminCmd::Ban { target: BanTarget::Name(n), time: Some(t) } if n == "mallory" && t.as_secs() == 7200));
+        assert!(matches!(parse("unban ::1"), AdminCmd::Unban(BanTarget::Ip(_))));
+        assert!(matches!(parse("persist room1 off"), AdminCmd::Persist { room, on: false } if room == "room1"));
+        assert!(matches!(parse("persist room1"), AdminCmd::Persist { on: true, .. }));
+        assert!(matches!(parse("bc * hello all"), AdminCmd::Broadcast { room: None, text } if text == "hello all"));
+        assert!(matches!(parse("broadcast 2 hi"), AdminCmd::Broadcast { room: Some(r), .. } if r == "2"));
+        assert!(matches!(parse("exit 10.0.0.2:8080"), AdminCmd::Exit { reconnect: Some(a) } if a == "10.0.0.2:8080"));
+        assert!(matches!(parse("exit"), AdminCmd::Exit { reconnect: None }));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for line in ["", "nope", "echo x", "kick", "ban", "ban bob 2w", "unban", "close", "persist", "persist r maybe", "bc *"] {
+            assert!(AdminCmd::parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn bans() {
+        let mut bans = Bans::default();
+        let bob = BanTarget::Name("bob".into());
+        let ip = BanTarget::parse("10.0.0.9");
+        assert!(bans.add(ip.clone(), None).is_none());
+        assert!(bans.add(bob.clone(), Some(Duration::from_secs(60))).is_some());
+        // 超出范围的时长当作永久
+        assert!(bans.add(BanTarget::parse("eve"), Some(Duration::MAX)).is_none());
+        assert!(bans.is_banned(&bob) && bans.is_banned(&ip));
+        assert!(!bans.is_banned(&BanTarget::parse("alice")));
+        // 保存后恢复
+        let mut loaded = Bans::default();
+        loaded.load(bans.save());
+        assert!(loaded.is_banned(&bob) && loaded.is_banned(&ip));
+        assert!(bans.remove(&bob) && !bans.remove(&bob));
+        assert!(!bans.is_banned(&bob));
+        // 过期的自动清除
+        bans.list.insert(bob.clone(), Some(Local::now() - chrono::Duration::seconds(1)));
+        assert!(!bans.is_banned(&bob));
+    }
+}
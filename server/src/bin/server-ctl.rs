@@ -0,0 +1,86 @@
+//! 通过unix socket向运行中的服务器发送管理指令
+//!
+//! server-ctl [-s socket路径] [指令]
+//! 不带指令时进入交互模式，每行一条指令
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    use std::{env, io::Write, process::exit};
+    use tokio::{io::{stdin, AsyncBufReadExt, BufReader}, net::UnixStream};
+
+    let mut path = net::admin_sock_path();
+    let mut words = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" | "--sock" => {
+                path = args.next().unwrap_or_else(|| {
+                    eprintln!("{} 需要指定socket路径", arg);
+                    exit(1);
+                });
+            },
+            "-h" | "--help" => {
+                println!("用法：server-ctl [-s socket路径] [指令]");
+                println!("不带指令时进入交互模式，输入help查看服务器支持的指令");
+                return;
+            },
+            _ => words.push(arg),
+        }
+    }
+    let mut stm = match UnixStream::connect(&path).await {
+        Ok(stm) => stm,
+        Err(e) => {
+            eprintln!("无法连接到{}：{}", path, e);
+            exit(1);
+        },
+    };
+    // 执行一条指令并输出结果
+    async fn exec(stm: &mut UnixStream, line: &str) -> bool {
+        if net::write(stm, line.as_bytes()).await.is_err() {
+            eprintln!("连接已断开");
+            return false;
+        }
+        match net::read(stm).await {
+            Ok(out) => {
+                println!("{}", String::from_utf8_lossy(&out));
+                true
+            },
+            Err(_) => {
+                eprintln!("连接已断开");
+                false
+            },
+        }
+    }
+    if !words.is_empty() {
+        if !exec(&mut stm, &words.join(" ")).await {
+            exit(1);
+        }
+        return;
+    }
+    let mut reader = BufReader::new(stdin()).lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush().ok();
+        let line = match reader.next_line().await {
+            Ok(Some(line)) => line,
+            _ => break,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "quit" {
+            break;
+        }
+        if !exec(&mut stm, line).await || line == "exit" {
+            break;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("server-ctl只支持unix系统");
+    std::process::exit(1);
+}
@@ -0,0 +1,148 @@
+//! 可选的HTTP服务，提供Prometheus格式的/metrics和JSON格式的/status
+
+use std::time::{Duration, Instant};
+use log::{debug, error, info};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use super::{admin::Admin, metrics::METRICS};
+
+// 请求头的最大长度
+const MAX_REQUEST: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn serve(addr: String, admin: Admin) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("无法监听HTTP地址 {}：{}", addr, e);
+            return;
+        },
+    };
+    info!("http run in {}", addr);
+    let started = Instant::now();
+    loop {
+        let (stm, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("http accept: {}", e);
+                continue;
+            },
+        };
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stm, &admin, started).await {
+                debug!("http {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stm: TcpStream, admin: &Admin, started: Instant) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 1024];
+    // 只需要请求行，读到请求头结束为止
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = match timeout(READ_TIMEOUT, stm.read(&mut tmp)).await {
+            Ok(res) => res?,
+            Err(_) => return Ok(()),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&tmp[..len]);
+        if buf.len() > MAX_REQUEST {
+            return respond(&mut stm, "431 Request Header Fields Too Large", "text/plain", "").await;
+        }
+    }
+    let req = String::from_utf8_lossy(&buf);
+    let mut parts = req.lines().next().unwrap_or("").split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "GET" {
+        return respond(&mut stm, "405 Method Not Allowed", "text/plain", "").await;
+    }
+    match path.split('?').next().unwrap_or("") {
+        "/metrics" => {
+            let body = metrics(admin).await;
+            respond(&mut stm, "200 OK", "text/plain; version=0.0.4", &body).await
+        },
+        "/status" => {
+            let body = status(admin, started).await;
+            respond(&mut stm, "200 OK", "application/json", &body).await
+        },
+        _ => respond(&mut stm, "404 Not Found", "text/plain", "not found\n").await,
+    }
+}
+
+async fn respond(stm: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, content_type, body.len());
+    stm.write_all(head.as_bytes()).await?;
+    stm.write_all(body.as_bytes()).await?;
+    stm.shutdown().await
+}
+
+async fn metrics(admin: &Admin) -> String {
+    // 断线后等待恢复的会话不算在线
+    let users = admin.users.lock().await.conns.len() as f64;
+    let rooms = admin.rooms.lock().await.by_id.len() as f64;
+    METRICS.render(&[
+        ("chat_online_users", "在线用户数", users),
+        ("chat_rooms", "房间数", rooms),
+    ])
+}
+
+async fn status(admin: &Admin, started: Instant) -> String {
+    let users = {
+        let users = admin.users.lock().await;
+        let mut list: Vec<_> = users.by_id.values().map(|u| {
+            let conn = users.conns.get(&u.id);
+            let (pkgs_in, bytes_in, pkgs_out, bytes_out) = conn.map_or((0, 0, 0, 0), |c| c.stats.get());
+            json!({
+                "id": u.id,
+                "name": u.name,
+                "addr": conn.map(|c| c.addr),
+                "online_secs": conn.map(|c| c.since.elapsed().as_secs()),
+                "packets_received": pkgs_in,
+                "bytes_received": bytes_in,
+                "packets_sent": pkgs_out,
+                "bytes_sent": bytes_out,
+            })
+        }).collect();
+        list.sort_by_key(|u| u["id"].as_u64());
+        list
+    };
+    let rooms = {
+        let rooms = admin.rooms.lock().await;
+        let mut list: Vec<_> = rooms.by_id.values().map(|r| {
+            let mut members: Vec<_> = r.cs.values().map(|c| json!({
+                "id": c.id,
+                "name": c.name,
+                "addr": c.addr,
+                "role": format!("{:?}", r.role(c.id)).to_lowercase(),
+            })).collect();
+            members.sort_by_key(|c| c["id"].as_u64());
+            json!({
+                "id": r.id,
+                "name": r.name,
+                "topic": r.topic,
+                "description": r.description,
+                "capacity": r.capacity,
+                "persistent": r.persistent,
+                "members": members,
+            })
+        }).collect();
+        list.sort_by_key(|r| r["id"].as_u64());
+        list
+    };
+    let status = json!({
+        "uptime_secs": started.elapsed().as_secs(),
+        "users": users,
+        "rooms": rooms,
+    });
+    serde_json::to_string_pretty(&status).unwrap() + "\n"
+}
+
@@ -0,0 +1,302 @@
+//! 限制每个IP的连接数、还没登录的连接数和登录失败次数，以及每个用户创建和加入房间的频率
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use log::warn;
+use net::ID;
+use super::metrics::{Metrics, METRICS};
+
+// 统计加入房间次数的时间窗口
+const JOIN_WINDOW: Duration = Duration::from_secs(60);
+// 每次登录失败后等待的时间，连续失败时递增
+const LOGIN_DELAY: Duration = Duration::from_millis(500);
+// 超过这个时间没有再失败，之前的登录失败次数清零
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+// 清理过期的登录失败记录的间隔
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 各项限制，0为不限
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// 每个IP同时保持的连接数
+    pub conns_per_ip: usize,
+    /// 所有IP加起来还没有登录的连接数
+    pub pending: usize,
+    /// 连接后必须在这个时间内登录
+    pub login_timeout: Duration,
+    /// 连续登录失败这么多次后锁定这个IP
+    pub login_attempts: u32,
+    /// 锁定的时间
+    pub lockout: Duration,
+    /// 每个用户同时作为房主的房间数
+    pub rooms_per_user: usize,
+    /// 每个用户每分钟加入房间的次数
+    pub joins_per_minute: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            conns_per_ip: 16,
+            pending: 128,
+            login_timeout: Duration::from_secs(30),
+            login_attempts: 5,
+            lockout: Duration::from_secs(5 * 60),
+            rooms_per_user: 5,
+            joins_per_minute: 20,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    // 最后一次失败的时间
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    /// 锁定已经结束，并且很久没有再失败
+    fn stale(&self, now: Instant) -> bool {
+        self.locked_until.is_none_or(|t| t <= now) && now.duration_since(self.last) >= FAILURE_WINDOW
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    conns: HashMap<IpAddr, usize>,
+    pending: usize,
+    failures: HashMap<IpAddr, Failures>,
+    joins: HashMap<ID, VecDeque<Instant>>,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    pub limits: Limits,
+    inner: Mutex<Inner>,
+}
+
+/// 连接断开时释放占用的连接数
+pub struct ConnGuard {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+    // 还没有登录
+    pending: bool,
+}
+
+impl ConnGuard {
+    pub fn limiter(&self) -> &Arc<Limiter> {
+        &self.limiter
+    }
+
+    /// 登录成功，不再占用未登录的连接数
+    pub fn authenticated(&mut self) {
+        if self.pending {
+            self.pending = false;
+            self.limiter.inner.lock().unwrap().pending -= 1;
+        }
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        if self.pending {
+            inner.pending -= 1;
+        }
+        if let Some(n) = inner.conns.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                inner.conns.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, inner: Mutex::new(Inner::default()) }
+    }
+
+    /// 占用一个连接数，超过限制时返回回复给客户端的原因
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Result<ConnGuard, &'static str> {
+        let mut inner = self.inner.lock().unwrap();
+        if self.limits.pending != 0 && inner.pending >= self.limits.pending {
+            drop(inner);
+            warn!("未登录的连接超过{}个，拒绝{}", self.limits.pending, ip);
+            Metrics::inc(&METRICS.rate_limited, 1);
+            return Err("Server busy");
+        }
+        let n = inner.conns.entry(ip).or_default();
+        if self.limits.conns_per_ip != 0 && *n >= self.limits.conns_per_ip {
+            drop(inner);
+            warn!("{}的连接数超过{}，拒绝连接", ip, self.limits.conns_per_ip);
+            Metrics::inc(&METRICS.rate_limited, 1);
+            return Err("Too many connections");
+        }
+        *n += 1;
+        inner.pending += 1;
+        Ok(ConnGuard { limiter: self.clone(), ip, pending: true })
+    }
+
+    /// IP是否因为登录失败太多被锁定
+    pub fn locked(&self, ip: IpAddr) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.failures.get(&ip).and_then(|f| f.locked_until) {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                // 锁定已过期
+                inner.failures.remove(&ip);
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// 记录一次用户名或密码无效的登录，返回失败后需要等待的时间，被锁定时返回None
+    pub fn login_failed(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let f = inner.failures.entry(ip).or_insert(Failures { count: 0, last: now, locked_until: None });
+        if f.stale(now) {
+            f.count = 0;
+            f.locked_until = None;
+        }
+        f.count += 1;
+        f.last = now;
+        if self.limits.login_attempts != 0 && f.count >= self.limits.login_attempts {
+            f.locked_until = Some(now + self.limits.lockout);
+            drop(inner);
+            warn!("{}连续登录失败{}次，锁定{}秒", ip, self.limits.login_attempts, self.limits.lockout.as_secs());
+            Metrics::inc(&METRICS.rate_limited, 1);
+            return None;
+        }
+        Some(LOGIN_DELAY * f.count)
+    }
+
+    pub fn login_ok(&self, ip: IpAddr) {
+        self.inner.lock().unwrap().failures.remove(&ip);
+    }
+
+    /// 定期清理过期的登录失败记录，不让只失败过几次的IP一直占着内存
+    pub async fn prune(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            self.inner.lock().unwrap().failures.retain(|_, f| !f.stale(now));
+        }
+    }
+
+    /// 记录一次加入房间，超过每分钟的次数时返回false
+    pub fn join(&self, uid: ID) -> bool {
+        if self.limits.joins_per_minute == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let joins = inner.joins.entry(uid).or_default();
+        while joins.front().is_some_and(|t| now.duration_since(*t) >= JOIN_WINDOW) {
+            joins.pop_front();
+        }
+        if joins.len() >= self.limits.joins_per_minute {
+            drop(inner);
+            warn!("User[id: {}] 每分钟加入房间超过{}次", uid, self.limits.joins_per_minute);
+            Metrics::inc(&METRICS.rate_limited, 1);
+            return false;
+        }
+        joins.push_back(now);
+        true
+    }
+
+    /// 用户退出后清除记录
+    pub fn forget(&self, uid: ID) {
+        self.inner.lock().unwrap().joins.remove(&uid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: Limits) -> Arc<Limiter> {
+        Arc::new(Limiter::new(limits))
+    }
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn conns_per_ip() {
+        let limiter = limiter(Limits { conns_per_ip: 2, pending: 0, ..Default::default() });
+        let a = limiter.connect(IP).unwrap();
+        let _b = limiter.connect(IP).unwrap();
+        assert_eq!(limiter.connect(IP).err(), Some("Too many connections"));
+        assert!(limiter.connect(OTHER).is_ok());
+        // 断开后释放
+        drop(a);
+        assert!(limiter.connect(IP).is_ok());
+    }
+
+    #[test]
+    fn pending() {
+        let limiter = limiter(Limits { conns_per_ip: 0, pending: 1, ..Default::default() });
+        let mut a = limiter.connect(IP).unwrap();
+        assert_eq!(limiter.connect(OTHER).err(), Some("Server busy"));
+        // 登录后不再算作未登录的连接
+        a.authenticated();
+        a.authenticated();
+        let b = limiter.connect(OTHER).unwrap();
+        drop(a);
+        drop(b);
+        let inner = limiter.inner.lock().unwrap();
+        assert_eq!(inner.pending, 0);
+        assert!(inner.conns.is_empty());
+    }
+
+    #[test]
+    fn lockout() {
+        let limiter = limiter(Limits { login_attempts: 3, ..Default::default() });
+        assert_eq!(limiter.login_failed(IP), Some(LOGIN_DELAY));
+        assert_eq!(limiter.login_failed(IP), Some(LOGIN_DELAY * 2));
+        assert!(!limiter.locked(IP));
+        assert_eq!(limiter.login_failed(IP), None);
+        assert!(limiter.locked(IP));
+        assert!(!limiter.locked(OTHER));
+        // 锁定过期后解除
+        limiter.inner.lock().unwrap().failures.get_mut(&IP).unwrap().locked_until = Some(Instant::now());
+        assert!(!limiter.locked(IP));
+    }
+
+    #[test]
+    fn failures_expire() {
+        let limiter = limiter(Limits { login_attempts: 3, ..Default::default() });
+        limiter.login_failed(IP);
+        limiter.login_failed(IP);
+        // 很久没有再失败，重新计数
+        limiter.inner.lock().unwrap().failures.get_mut(&IP).unwrap().last = Instant::now() - FAILURE_WINDOW;
+        assert_eq!(limiter.login_failed(IP), Some(LOGIN_DELAY));
+        limiter.login_ok(IP);
+        assert!(limiter.inner.lock().unwrap().failures.is_empty());
+    }
+
+    #[test]
+    fn joins_per_minute() {
+        let limiter = limiter(Limits { joins_per_minute: 2, ..Default::default() });
+        assert!(limiter.join(1) && limiter.join(1));
+        assert!(!limiter.join(1));
+        assert!(limiter.join(2));
+        // 一分钟以前的不再计入
+        limiter.inner.lock().unwrap().joins.get_mut(&1).unwrap()[0] = Instant::now() - JOIN_WINDOW;
+        assert!(limiter.join(1));
+        limiter.forget(1);
+        assert!(!limiter.inner.lock().unwrap().joins.contains_key(&1));
+        let unlimited = self::limiter(Limits { joins_per_minute: 0, ..Default::default() });
+        assert!((0..100).all(|_| unlimited.join(1)));
+    }
+}
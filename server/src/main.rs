@@ -1,13 +1,44 @@
 use log::{debug, error, info, warn};
 use tokio::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{env, process::exit, io::Write};
-use std::{fmt::Debug, time::Duration};
-use std::{net::SocketAddr, str, sync::Arc};
+use std::{fmt::Debug, time::{Duration, Instant}};
+use std::{net::{IpAddr, SocketAddr}, path::PathBuf, str, sync::Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
 use net::*;
-use tokio::{sync::*, io::*, time::sleep};
+use tokio::{sync::*, io::*, time::{interval_at, sleep, sleep_until, timeout}};
+use admin::{Admin, AdminCmd, BanTarget, Bans};
+use limit::{ConnGuard, Limiter, Limits};
+use metrics::{Metrics, METRICS};
+use passwd::PasswdHash;
+use state::{SavedRoom, State};
+
+mod admin;
+mod http;
+mod limit;
+mod metrics;
+mod moderation;
+mod passwd;
+mod state;
 
 const LISTEN_ADDR: &str = "0.0.0.0:5566";
+// 关闭时保存状态的文件
+const STATE_FILE: &str = "server-state.json";
+// 关闭时等待客户端连接处理完的时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// 默认多久没有收到客户端的数据包就断开连接，客户端每5秒发送一次心跳包
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// 服务端发送心跳包的间隔
+const HEARTBEAT: Duration = Duration::from_secs(5 * 60);
+// 客户端断线后保留会话的时间，期间可以用token恢复
+const RESUME_GRACE: Duration = Duration::from_secs(60);
+// 协调两个客户端连接时，让双方等待这么久再同时连接，抵消两边收到通知的时间差
+const PUNCH_DELAY: Duration = Duration::from_secs(1);
+// 连接失败后双方再次连接的间隔和总共尝试的次数
+const PUNCH_INTERVAL: Duration = Duration::from_secs(2);
+const PUNCH_ATTEMPTS: u32 = 3;
+// 按时间表连接完之后，最多再等这么久客户端的报告
+const PUNCH_REPORT_GRACE: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() {
@@ -33,20 +64,107 @@ async fn main() {
         .init();
     // 默认端口5566
     let mut addr: String = LISTEN_ADDR.into();
-    if env::args().len() > 1 {
-        let mut args = env::args();
-        let port = { args.nth(1).unwrap() };
-        addr = format!("0.0.0.0:{}", port);
+    #[cfg(unix)]
+    let mut admin_sock = admin_sock_path();
+    let mut http_addr = None;
+    let mut state_path = PathBuf::from(STATE_FILE);
+    let mut idle_timeout = IDLE_TIMEOUT;
+    let mut resume_grace = RESUME_GRACE;
+    let mut limits = Limits::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--state" => {
+                state_path = args.next().map(PathBuf::from).unwrap_or_else(|| {
+                    error!("--state 需要指定文件路径");
+                    exit(1);
+                });
+            },
+            "--idle-timeout" => {
+                idle_timeout = Duration::from_secs(number_arg(&mut args, &arg, "秒数"));
+            },
+            "--resume-grace" => {
+                resume_grace = Duration::from_secs(number_arg(&mut args, &arg, "秒数"));
+            },
+            "--max-conns-per-ip" => {
+                limits.conns_per_ip = number_arg(&mut args, &arg, "连接数");
+            },
+            "--max-pending" => {
+                limits.pending = number_arg(&mut args, &arg, "连接数");
+            },
+            "--login-timeout" => {
+                limits.login_timeout = Duration::from_secs(number_arg(&mut args, &arg, "秒数"));
+            },
+            "--login-attempts" => {
+                limits.login_attempts = number_arg(&mut args, &arg, "次数");
+            },
+            "--lockout" => {
+                limits.lockout = Duration::from_secs(number_arg(&mut args, &arg, "秒数"));
+            },
+            "--max-rooms-per-user" => {
+                limits.rooms_per_user = number_arg(&mut args, &arg, "房间数");
+            },
+            "--joins-per-minute" => {
+                limits.joins_per_minute = number_arg(&mut args, &arg, "次数");
+            },
+            "--http" => {
+                http_addr = Some(args.next().unwrap_or_else(|| {
+                    error!("--http 需要指定监听地址，例如127.0.0.1:9100");
+                    exit(1);
+                }));
+            },
+            #[cfg(unix)]
+            "--admin" => {
+                admin_sock = args.next().unwrap_or_else(|| {
+                    error!("--admin 需要指定socket路径");
+                    exit(1);
+                });
+            },
+            port => {
+                addr = format!("0.0.0.0:{}", port);
+            },
+        }
+    }
+    let mut server = Server::new(&addr).await;
+    server.http_addr = http_addr;
+    server.state_path = state_path;
+    server.idle_timeout = idle_timeout;
+    server.resume_grace = resume_grace;
+    server.limits = limits;
+    #[cfg(unix)]
+    { server.admin_sock = Some(admin_sock); }
+    server.run().await;
+    // 读取stdin的线程不会自己结束，直接退出
+    exit(0);
+}
+
+/// 读取参数后面的数字，没有或者不是数字时退出
+fn number_arg<T: str::FromStr>(args: &mut impl Iterator<Item = String>, arg: &str, what: &str) -> T {
+    match args.next().and_then(|t| t.parse().ok()) {
+        Some(n) => n,
+        None => {
+            error!("{} 需要指定{}", arg, what);
+            exit(1);
+        },
     }
-    Server::new(&addr).await
-        .run().await;
 }
 
 struct Server {
     addr: String,
     listener: TcpListener,
+    // 管理socket的路径
+    #[cfg(unix)]
+    admin_sock: Option<String>,
+    // 提供/metrics和/status的HTTP地址
+    http_addr: Option<String>,
+    state_path: PathBuf,
+    idle_timeout: Duration,
+    resume_grace: Duration,
+    // 连接数、登录失败次数等限制
+    limits: Limits,
     rooms: Arc<Mutex<AllRoomInfo>>,
     users: Arc<Mutex<AllUserInfo>>,
+    bans: Arc<Mutex<Bans>>,
 }
 
 impl Server {
@@ -64,113 +182,421 @@ impl Server {
             },
             rooms: Arc::new(Mutex::new(AllRoomInfo::new())),
             users: Arc::new(Mutex::new(AllUserInfo::default())),
+            bans: Arc::new(Mutex::new(Bans::default())),
+            #[cfg(unix)]
+            admin_sock: None,
+            http_addr: None,
+            state_path: PathBuf::from(STATE_FILE),
+            idle_timeout: IDLE_TIMEOUT,
+            resume_grace: RESUME_GRACE,
+            limits: Limits::default(),
         }
     }
 
     async fn run(self) {
         info!("server run in {}", self.addr);
+        info!("限制：{:?}（0为不限）", self.limits);
+        let state = State::load(&self.state_path);
+        self.bans.lock().await.load(state.bans);
+        self.rooms.lock().await.load(state.rooms);
 
-        let t1 = tokio::spawn(Self::poll_cmd(self.rooms.clone(), self.users.clone()));
-        let t2 = tokio::spawn(Self::accept(self.listener, self.rooms.clone(), self.users.clone()));
-        tokio::join!(t1, t2).0.unwrap();
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        let admin = Admin {
+            rooms: self.rooms.clone(),
+            users: self.users.clone(),
+            bans: self.bans.clone(),
+            shutdown: shutdown_tx,
+        };
+        #[cfg(unix)]
+        if let Some(path) = &self.admin_sock {
+            let listener = bind_admin_sock(path).unwrap_or_else(|e| {
+                error!("无法创建管理socket {}：{}", path, e);
+                exit(1);
+            });
+            info!("admin socket: {}", path);
+            tokio::spawn(Self::poll_admin_sock(listener, admin.clone()));
+        }
+        if let Some(addr) = self.http_addr {
+            tokio::spawn(http::serve(addr, admin.clone()));
+        }
+        tokio::spawn(Self::poll_cmd(admin));
+        let limiter = Arc::new(Limiter::new(self.limits));
+        tokio::spawn(limiter.clone().prune());
+        let accept = tokio::spawn(Self::accept(self.listener, self.rooms.clone(), self.users.clone(),
+                self.bans.clone(), limiter, self.idle_timeout, self.resume_grace));
+        // 等待exit指令或者SIGINT、SIGTERM
+        let reconnect = tokio::select! {
+            reconnect = shutdown_rx.recv() => reconnect.flatten(),
+            _ = wait_signal() => None,
+        };
+        info!("正在关闭服务器");
+        // 不再接受新的连接
+        accept.abort();
+        // 通知所有客户端，等待它们的连接处理完
+        // 队列已满的连接不等待，超时后直接关闭，不能让一个卡住的连接拖住整个服务器
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        let txs: Vec<_> = self.users.lock().await.conns.values().map(|c| c.tx.clone()).collect();
+        for tx in txs.iter() {
+            if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(Event::Shutdown(reconnect.clone())) {
+                warn!("连接的消息队列已满，无法通知它服务器关闭");
+            }
+        }
+        while !self.users.lock().await.conns.is_empty() {
+            if Instant::now() >= deadline {
+                warn!("还有{}个连接没有关闭", self.users.lock().await.conns.len());
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        let state = State {
+            bans: self.bans.lock().await.save(),
+            rooms: self.rooms.lock().await.save(),
+        };
+        match state.save(&self.state_path) {
+            Ok(_) => info!("服务器状态已保存到{}", self.state_path.display()),
+            Err(e) => error!("无法保存服务器状态到{}：{}", self.state_path.display(), e),
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.admin_sock {
+            std::fs::remove_file(path).ok();
+        }
+        info!("服务器已关闭");
     }
 
     /// 处理新接入的客户端
     /// 创建一个任务处理
-    async fn accept(listener: TcpListener,  rooms: Arc<Mutex<AllRoomInfo>>, users: Arc<Mutex<AllUserInfo>>) {
+    async fn accept(listener: TcpListener,  rooms: Arc<Mutex<AllRoomInfo>>, users: Arc<Mutex<AllUserInfo>>,
+            bans: Arc<Mutex<Bans>>, limiter: Arc<Limiter>, idle_timeout: Duration, resume_grace: Duration
+        ) {
         loop {
-            let (stm, addr) = listener.accept().await.unwrap();
+            let (mut stm, addr) = listener.accept().await.unwrap();
             debug!("New peer: {}", addr);
+            let guard = match limiter.connect(addr.ip()) {
+                Ok(guard) => guard,
+                Err(reason) => {
+                    // 不要让拒绝的应答阻塞accept
+                    tokio::spawn(async move {
+                        write(&mut stm, reason.as_bytes()).await.ok();
+                    });
+                    continue;
+                },
+            };
             // 创建任务处理
-            tokio::spawn(CertificationCenter::poll(stm, addr, rooms.clone(), users.clone()));
+            tokio::spawn(CertificationCenter::poll(stm, addr, rooms.clone(), users.clone(), bans.clone(),
+                    guard, idle_timeout, resume_grace));
         }
     }
 
-    /// 处理标准输入
-    async fn poll_cmd(rooms: Arc<Mutex<AllRoomInfo>>, users: Arc<Mutex<AllUserInfo>>) {
+    /// 处理标准输入的管理指令
+    async fn poll_cmd(admin: Admin) {
         let mut reader = BufReader::new(stdin());
         loop {
             let mut buf = String::new();
             reader.read_line(&mut buf).await.unwrap();
-            if buf.len() == 0 {
+            if buf.is_empty() {
                 warn!("stdin has been closed");
                 break;
             }
-            if String::from(buf.trim()).to_uppercase() == "echo rooms".to_uppercase() {
-                let lock = rooms.lock().await;
-                println!("{:#?}", &lock as &AllRoomInfo);
-            }
-            else if String::from(buf.trim()).to_uppercase() == "echo users".to_uppercase() {
-                let lock = users.lock().await;
-                println!("{:#?}", &lock as &AllUserInfo);
+            if buf.trim().is_empty() {
+                continue;
             }
-            else if String::from(buf.trim()).to_uppercase() == "exit".to_uppercase() {
-                exit(0);
+            match AdminCmd::parse(&buf) {
+                Ok(cmd) => println!("{}", admin.exec(cmd).await),
+                Err(e) => println!("{}", e),
             }
         }
     }
+
+    /// 在unix socket上接收server-ctl发来的管理指令
+    /// 每个数据包是一条指令，回复一个数据包作为指令的输出
+    #[cfg(unix)]
+    async fn poll_admin_sock(listener: tokio::net::UnixListener, admin: Admin) {
+        loop {
+            let mut stm = match listener.accept().await {
+                Ok((stm, _)) => stm,
+                Err(e) => {
+                    warn!("admin socket: {}", e);
+                    continue;
+                },
+            };
+            let admin = admin.clone();
+            tokio::spawn(async move {
+                while let Ok(pkg) = read(&mut stm).await {
+                    let line = String::from_utf8_lossy(&pkg).to_string();
+                    info!("admin: {}", line.trim());
+                    let out = match AdminCmd::parse(&line) {
+                        Ok(cmd) => admin.exec(cmd).await,
+                        Err(e) => e,
+                    };
+                    if write(&mut stm, out.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// 创建管理socket，只允许启动服务器的用户连接
+/// 已经有服务器在使用这个路径时不启动，避免删掉它的socket
+#[cfg(unix)]
+fn bind_admin_sock(path: &str) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "路径已存在且不是socket"));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(Error::new(ErrorKind::AddrInUse, "已经有服务器在使用"));
+        }
+        // 上次没有清理的socket文件
+        std::fs::remove_file(path)?;
+    }
+    // bind时创建的文件就是600，不留其他用户可以连接的时间窗口
+    let mask = unsafe { libc::umask(0o077) };
+    let listener = tokio::net::UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    let listener = listener?;
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+        std::fs::remove_file(path).ok();
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+/// 等待SIGINT（Ctrl+C）或SIGTERM
+async fn wait_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = term.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
 }
 
 /// 注册中心，确保客户端成功登录
 struct CertificationCenter;
 
 impl CertificationCenter {
+    #[allow(clippy::too_many_arguments)]
     async fn poll(mut stm: TcpStream, addr: SocketAddr,
-            rooms: Arc<Mutex<AllRoomInfo>>, users: Arc<Mutex<AllUserInfo>>
+            rooms: Arc<Mutex<AllRoomInfo>>, users: Arc<Mutex<AllUserInfo>>, bans: Arc<Mutex<Bans>>,
+            mut guard: ConnGuard, idle_timeout: Duration, resume_grace: Duration
         ) {
-        let mut user = match Self::wait_login(&mut stm, users.clone()).await {
-            Ok(u) => u,
+        if bans.lock().await.is_banned(&BanTarget::Ip(addr.ip())) {
+            info!("拒绝被封禁的地址：{}", addr);
+            Metrics::inc(&METRICS.login_failures, 1);
+            write(&mut stm, "You are banned".as_bytes()).await.ok();
+            return;
+        }
+        let limiter = guard.limiter().clone();
+        if limiter.locked(addr.ip()) {
+            info!("拒绝登录失败次数过多的地址：{}", addr);
+            Metrics::inc(&METRICS.rate_limited, 1);
+            write(&mut stm, "Too many attempts".as_bytes()).await.ok();
+            return;
+        }
+        let login = Self::wait_login(&mut stm, users.clone(), bans, &limiter);
+        // 不能让不登录的连接一直占着
+        let login = if limiter.limits.login_timeout.is_zero() {
+            login.await
+        } else {
+            match timeout(limiter.limits.login_timeout, login).await {
+                Ok(login) => login,
+                Err(_) => {
+                    warn!("客户端[{}]{}秒内没有登录，断开连接", addr, limiter.limits.login_timeout.as_secs());
+                    Metrics::inc(&METRICS.login_failures, 1);
+                    write(&mut stm, "Login timeout".as_bytes()).await.ok();
+                    return;
+                },
+            }
+        };
+        let login = match login {
+            Ok(login) => login,
             Err(e) => {
                 warn!("客户端[{}]用户登录失败 {}", addr, e);
                 return ;
             }
         };
-        {
-            let mut users = users.lock().await;
-            users.insert(&mut user);
-        }
-        write(&mut stm, "OK".as_bytes()).await.unwrap();
+        guard.authenticated();
+        let (tx, rx) = mpsc::channel::<Event>(64);
+        let stats = Arc::new(Stats::default());
+        let conn = Conn {
+            addr,
+            tx: tx.clone(),
+            stats: stats.clone(),
+            since: Instant::now(),
+        };
+        // 每次登录或恢复都换一个新的token
+        let token = Secret::from(format!("{:032x}", rand::random::<u128>()));
+        let (user, detached) = match login {
+            Login::New(mut user) => {
+                users.lock().await.insert(&mut user, conn);
+                (user, None)
+            },
+            Login::Resume(detached) => {
+                users.lock().await.conns.insert(detached.user.id, conn);
+                (detached.user.clone(), Some(detached))
+            },
+        };
+        write(&mut stm, "OK".as_bytes()).await.ok();
         // 将用户信息反馈给客户端
         let base_info = BaseUserInfo {
             id: user.id,
             name: user.name.clone(),
         };
-        write(&mut stm, &serde_json::to_vec(&base_info).unwrap()).await.unwrap();
+        let session = Session {
+            id: user.id,
+            name: user.name.clone(),
+            token: token.clone(),
+        };
+        write(&mut stm, &serde_json::to_vec(&session).unwrap()).await.ok();
         info!("{}: {:?}", &addr, &user);
         let uid = user.id;
-        let mut prcs = Peer::new(user, stm, addr, rooms.clone());
+        let mut prcs = Peer::new(user, stm, addr, rooms.clone(), tx, rx, stats, limiter.clone(), idle_timeout);
+        if let Some(detached) = detached {
+            info!("{}: Resume {:?}", addr, prcs.user);
+            prcs.room = Self::rejoin(&rooms, &prcs, detached).await;
+        }
         prcs.poll().await.ok();
-        {
-            let mut users = users.lock().await;
-            users.remove(uid);
+        // 连接已经断开，不再占用这个IP的连接数
+        drop(guard);
+        if prcs.resumable {
+            // 保留会话，等待客户端重连
+            let mut members = HashMap::new();
+            {
+                let lock = rooms.lock().await;
+                for rid in prcs.room.iter() {
+                    if let Some(room) = lock.by_id.get(rid) {
+                        members.insert(*rid, room.cs.keys().copied().filter(|id| *id != uid).collect());
+                    }
+                }
+            }
+            users.lock().await.detach(token.clone(), Detached {
+                user: prcs.user.clone(),
+                rooms: prcs.room.clone(),
+                members,
+            });
+            info!("{}: Detach {:?}，{}秒内可以恢复", prcs.addr, prcs.user, resume_grace.as_secs());
+            sleep(resume_grace).await;
+            // 已经恢复的会话会被取走
+            let detached = users.lock().await.detached.remove(&token);
+            if let Some(detached) = detached {
+                users.lock().await.remove(uid);
+                limiter.forget(uid);
+                info!("{}: Quit {:?}，会话已过期", prcs.addr, prcs.user);
+                Self::leave_rooms(&rooms, &base_info, &detached.rooms, &prcs.quit_reason).await;
+            }
+        } else {
+            users.lock().await.remove(uid);
+            limiter.forget(uid);
             info!("{}: Quit {:?}", prcs.addr, prcs.user);
-        } {
-            // 退出
+            if !prcs.shutdown {
+                Self::leave_rooms(&rooms, &base_info, &prcs.room, &prcs.quit_reason).await;
+            }
+        }
+    }
+
+    /// 恢复会话后重新加入之前的房间，返回仍然存在的房间
+    /// 断线期间加入房间的成员，通知双方相互连接
+    async fn rejoin(rooms: &Arc<Mutex<AllRoomInfo>>, peer: &Peer, detached: Detached) -> Vec<ID> {
+        let me = ClientInfo {
+            id: peer.user.id,
+            name: peer.user.name.clone(),
+            addr: peer.addr,
+        };
+        let mut joined = Vec::new();
+        let mut notify = Vec::new();
+        let mut punches = Vec::new();
+        {
             let mut lock = rooms.lock().await;
-            for rid in prcs.room.iter() {
-                if let None = lock.by_id.get(&rid) {
+            for rid in detached.rooms.iter() {
+                let room = match lock.by_id.get_mut(rid) {
+                    Some(room) if room.cs.contains_key(&me.id) => room,
+                    _ => {
+                        notify.push((peer.tx.clone(), Event::RoomClosed(*rid)));
+                        continue;
+                    },
+                };
+                // 地址可能已经变了
+                let c = room.cs.get_mut(&me.id).unwrap();
+                c.addr = me.addr;
+                c.tx = peer.tx.clone();
+                let known = detached.members.get(rid);
+                for c in room.cs.values() {
+                    if c.id == me.id || known.is_some_and(|k| k.contains(&c.id)) {
+                        continue;
+                    }
+                    punches.push(c.id);
+                    notify.push((c.tx.clone(), Event::Connect(*rid, me.clone())));
+                    notify.push((peer.tx.clone(), Event::Connect(*rid, ClientInfo {
+                        id: c.id,
+                        name: c.name.clone(),
+                        addr: c.addr,
+                    })));
+                }
+                joined.push(*rid);
+            }
+            for id in punches {
+                lock.punches.schedule(me.id, id);
+            }
+        }
+        for (tx, ev) in notify {
+            tx.send(ev).await.ok();
+        }
+        joined
+    }
+
+    /// 从房间中移除用户，通知房间内的其他成员
+    async fn leave_rooms(rooms: &Arc<Mutex<AllRoomInfo>>, user: &BaseUserInfo, rids: &[ID], reason: &str) {
+        let mut notify = Vec::new();
+        {
+            let mut lock = rooms.lock().await;
+            for rid in rids.iter() {
+                if !lock.by_id.contains_key(rid) {
                     continue;
                 }
                 // 获取删除自己后房间剩余的人数
                 let len: usize = {
                     let rom = lock.by_id.get_mut(rid).unwrap();
-                    rom.cs.remove(&prcs.user.id);
+                    rom.cs.remove(&user.id);
+                    rom.mods.remove(&user.id);
                     info!("User[id: {}, name: \"{}\"] remove from Room[id: {}, name: \"{}\"]",
-                            base_info.id, base_info.name, rom.id, rom.name);
+                            user.id, user.name, rom.id, rom.name);
+                    for c in rom.cs.values() {
+                        notify.push((c.tx.clone(), Event::PeerLeft {
+                            room: *rid,
+                            user: user.clone(),
+                            reason: reason.into(),
+                        }));
+                    }
+                    if rom.owner == Some(user.id) {
+                        rom.pick_owner(&mut notify);
+                    }
                     rom.cs.len()
                 };
-                // 如果房间为空了就删除房间
-                if len == 0 {
+                // 如果房间为空了就删除房间，常驻房间除外
+                if len == 0 && !lock.by_id[rid].persistent {
                     let rom = lock.remove(*rid);
                     info!("{:?} was destroyed", rom);
                 }
             }
         }
+        for (tx, ev) in notify {
+            tx.send(ev).await.ok();
+        }
     }
 
-    /// 等待用户登录
-    /// 成功返回用户信息
-    async fn wait_login(mut stm: &mut TcpStream, users: Arc<Mutex<AllUserInfo>>) -> Result<User> {
+    /// 等待用户登录或者恢复会话
+    async fn wait_login(stm: &mut TcpStream, users: Arc<Mutex<AllUserInfo>>, bans: Arc<Mutex<Bans>>,
+            limiter: &Limiter
+        ) -> Result<Login> {
+        let ip = stm.peer_addr()?.ip();
         loop {
             let pack = match read(stm).await {
                 Ok(pkg) => { pkg },
@@ -178,8 +604,8 @@ impl CertificationCenter {
                     match e {
                         ErrorType::IO(e) => { return Err(e); },
                         ErrorType::MissingHead(head) => {
-                            if head.len() == 0 {
-                                break Err(std::io::ErrorKind::Other.into());
+                            if head.is_empty() {
+                                break Err(Error::new(ErrorKind::UnexpectedEof, "连接已关闭"));
                             }
                             continue;
                         },
@@ -190,33 +616,116 @@ impl CertificationCenter {
                     }
                 },
             };
-            if pack.len() == 0 {
+            if pack.is_empty() {
+                continue;
+            }
+            if let Ok(r) = serde_json::from_slice::<Resume>(&pack) {
+                let detached = users.lock().await.detached.remove(&r.token);
+                match detached {
+                    Some(d) if bans.lock().await.is_banned(&BanTarget::Name(d.user.name.clone())) => {
+                        // 断线期间被封禁，会话作废
+                        users.lock().await.detached.insert(r.token, d);
+                        Self::refuse(stm, "User is banned").await?;
+                    },
+                    Some(d) => {
+                        limiter.login_ok(ip);
+                        break Ok(Login::Resume(d));
+                    },
+                    None => Self::refuse(stm, "Session expired").await?,
+                }
                 continue;
             }
             if let Ok(u) = serde_json::from_slice::<User>(&pack) {
-                let users = users.lock().await;
-                if u.name.len() != 0 && u.passwd.len() != 0 {
+                if bans.lock().await.is_banned(&BanTarget::Name(u.name.clone())) {
+                    Self::refuse(stm, "User is banned").await?;
+                    continue;
+                }
+                if !u.name.is_empty() && !u.passwd.is_empty() {
                     // 账号已存在
-                    if let Some(_) = users.by_name.get(&u.name) {
-                        write(&mut stm, "User already exists".as_bytes()).await?;
+                    let exists = users.lock().await.by_name.contains_key(&u.name);
+                    if exists {
+                        Self::refuse(stm, "User already exists").await?;
                         continue;
                     } else {
                         // 返回用户信息
-                        break Ok(u)
+                        limiter.login_ok(ip);
+                        break Ok(Login::New(u))
                     }
                 }
             }
-            write(&mut stm, "Fail to login user".as_bytes()).await?;
+            Self::reject(stm, limiter, ip, "Fail to login user").await?;
+        }
+    }
+
+    /// 拒绝登录，但用户名和密码没有问题，不计入这个IP的失败次数
+    async fn refuse(stm: &mut TcpStream, reason: &str) -> Result<()> {
+        Metrics::inc(&METRICS.login_failures, 1);
+        write(stm, reason.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// 登录失败，等待一段时间后回复原因，失败次数过多时断开连接
+    async fn reject(stm: &mut TcpStream, limiter: &Limiter, ip: IpAddr, reason: &str) -> Result<()> {
+        Metrics::inc(&METRICS.login_failures, 1);
+        match limiter.login_failed(ip) {
+            Some(delay) => {
+                sleep(delay).await;
+                write(stm, reason.as_bytes()).await?;
+                Ok(())
+            },
+            None => {
+                write(stm, "Too many attempts".as_bytes()).await.ok();
+                Err(Error::other("登录失败次数过多"))
+            },
         }
     }
 }
 
+/// 登录的结果
+enum Login {
+    New(User),
+    Resume(Detached),
+}
+
+/// 断线后等待重连的用户，仍然占用用户名和房间成员的位置
+#[derive(Debug)]
+struct Detached {
+    user: User,
+    rooms: Vec<ID>,
+    // 断线时每个房间里的其他成员，恢复后用来找出断线期间新加入的成员
+    members: HashMap<ID, HashSet<ID>>,
+}
+
 #[derive(Debug)]
 struct AllRoomInfo {
     by_id: HashMap<u32, RoomFull>,
     by_name: HashMap<String, u32>,
     // 当一个房间被删除时会将房间ID存入，以便取用
     unuse_id: Vec<u32>,
+    punches: Punches,
+}
+
+/// 安排了打洞、还没有收到结果的成员，双方都会报告，每一对只统计一次
+#[derive(Debug, Default)]
+struct Punches(HashMap<(ID, ID), Instant>);
+
+impl Punches {
+    fn key(a: ID, b: ID) -> (ID, ID) {
+        (a.min(b), a.max(b))
+    }
+
+    /// 安排a和b连接，过了时间表一段时间还没有报告的不再等待
+    fn schedule(&mut self, a: ID, b: ID) {
+        let now = Instant::now();
+        self.0.retain(|_, until| *until > now);
+        let until = now + PUNCH_DELAY + PUNCH_INTERVAL * PUNCH_ATTEMPTS + PUNCH_REPORT_GRACE;
+        self.0.insert(Self::key(a, b), until);
+    }
+
+    /// 收到a报告和b的连接结果，是这一对的第一个报告时返回true
+    fn report(&mut self, a: ID, b: ID) -> bool {
+        self.0.remove(&Self::key(a, b)).is_some_and(|until| until > Instant::now())
+    }
 }
 
 impl AllRoomInfo {
@@ -225,6 +734,21 @@ impl AllRoomInfo {
             by_id: HashMap::new(),
             by_name: HashMap::new(),
             unuse_id: Vec::new(),
+            punches: Punches::default(),
+        }
+    }
+
+    /// 请求加入的已有房间，None表示按名字新建房间
+    /// 指定了id（或者用邀请码找到了id）的只能加入已有的房间，新建房间的id由服务端分配
+    fn find(by_id: &HashMap<u32, RoomFull>, by_name: &HashMap<String, u32>, room: &Room
+    ) -> std::result::Result<Option<ID>, &'static str> {
+        if !room.invite.is_empty() || room.id != 0 {
+            match by_id.contains_key(&room.id) {
+                true => Ok(Some(room.id)),
+                false => Err("Room does not exist"),
+            }
+        } else {
+            Ok(by_name.get(&room.name).copied())
         }
     }
 
@@ -234,37 +758,172 @@ impl AllRoomInfo {
         self.unuse_id.push(id);
         room
     }
+
+    /// 保存常驻房间
+    fn save(&self) -> Vec<SavedRoom> {
+        self.by_id.values()
+            .filter(|r| r.persistent)
+            .map(|r| SavedRoom {
+                id: r.id,
+                name: r.name.clone(),
+                passwd_hash: Some(r.passwd.clone()),
+                passwd: None,
+                topic: r.topic.clone(),
+                description: r.description.clone(),
+                capacity: r.capacity,
+                bans: r.bans.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    fn load(&mut self, rooms: Vec<SavedRoom>) {
+        for r in rooms {
+            if self.by_id.contains_key(&r.id) || self.by_name.contains_key(&r.name) {
+                warn!("常驻房间[{}] {}与已有的房间冲突，跳过", r.id, r.name);
+                continue;
+            }
+            let passwd = match (r.passwd_hash, r.passwd) {
+                (Some(hash), _) => hash,
+                (None, Some(passwd)) => PasswdHash::new(&passwd),
+                (None, None) => {
+                    warn!("常驻房间[{}] {}没有密码，跳过", r.id, r.name);
+                    continue;
+                },
+            };
+            info!("恢复常驻房间[{}] {}", r.id, r.name);
+            self.by_name.insert(r.name.clone(), r.id);
+            self.by_id.insert(r.id, RoomFull {
+                id: r.id,
+                name: r.name,
+                passwd,
+                topic: r.topic,
+                description: r.description,
+                capacity: r.capacity,
+                persistent: true,
+                cs: HashMap::new(),
+                owner: None,
+                mods: HashSet::new(),
+                bans: r.bans.into_iter().collect(),
+                invites: HashMap::new(),
+            });
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 struct AllUserInfo {
     by_id: HashMap<ID, User>,
     by_name: HashMap<String, ID>,
+    // 每个在线用户的连接
+    conns: HashMap<ID, Conn>,
+    // 断线后等待恢复的会话，以token为键
+    detached: HashMap<Secret, Detached>,
     // 当一个房间被删除时会将房间ID存入，以便取用
     unuse_id: Vec<ID>,
 }
 
 impl AllUserInfo {
-    fn insert(&mut self, u: &mut User) {
-        if self.unuse_id.len() != 0 {
+    fn insert(&mut self, u: &mut User, conn: Conn) {
+        if !self.unuse_id.is_empty() {
             u.id = self.unuse_id.pop().unwrap();
         } else {
             u.id = self.by_id.len() as ID;
-            while let Some(_) = self.by_id.get(&u.id) {
+            while self.by_id.contains_key(&u.id) {
                 u.id += 1;
             }
         }
         self.by_name.insert(u.name.clone(), u.id);
         self.by_id.insert(u.id, u.clone());
+        self.conns.insert(u.id, conn);
+    }
+
+    /// 连接断开但保留用户信息
+    fn detach(&mut self, token: Secret, detached: Detached) {
+        self.conns.remove(&detached.user.id);
+        self.detached.insert(token, detached);
     }
 
     fn remove(&mut self, id: ID) {
+        self.conns.remove(&id);
         let user = self.by_id.remove(&id).unwrap();
         self.by_name.remove(&user.name);
         self.unuse_id.push(id);
     }
 }
 
+/// 在线用户的连接
+#[derive(Debug)]
+struct Conn {
+    addr: SocketAddr,
+    tx: mpsc::Sender<Event>,
+    stats: Arc<Stats>,
+    since: Instant,
+}
+
+/// 每个连接收发的数据包数和字节数
+#[derive(Debug, Default)]
+struct Stats {
+    pkgs_in: AtomicU64,
+    bytes_in: AtomicU64,
+    pkgs_out: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Stats {
+    fn recv(&self, len: usize) {
+        self.pkgs_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64 + 8, Ordering::Relaxed);
+        Metrics::inc(&METRICS.pkgs_in, 1);
+        Metrics::inc(&METRICS.bytes_in, len as u64 + 8);
+    }
+
+    fn send(&self, len: usize) {
+        self.pkgs_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64 + 8, Ordering::Relaxed);
+        Metrics::inc(&METRICS.pkgs_out, 1);
+        Metrics::inc(&METRICS.bytes_out, len as u64 + 8);
+    }
+
+    /// (收包, 收字节, 发包, 发字节)
+    fn get(&self) -> (u64, u64, u64, u64) {
+        (self.pkgs_in.load(Ordering::Relaxed), self.bytes_in.load(Ordering::Relaxed),
+            self.pkgs_out.load(Ordering::Relaxed), self.bytes_out.load(Ordering::Relaxed))
+    }
+}
+
+/// 发给处理客户端连接的task的事件
+#[derive(Debug)]
+enum Event {
+    /// 有新成员加入房间，通知客户端去连接
+    Connect(ID, ClientInfo),
+    /// 服务器公告
+    Notice(String),
+    /// 被管理员踢出
+    Kick(String),
+    /// 房间被管理员关闭
+    RoomClosed(ID),
+    /// 服务器关闭，附带重连地址
+    Shutdown(Option<String>),
+    /// 房间内有成员离开
+    PeerLeft { room: ID, user: BaseUserInfo, reason: String },
+    /// 房间内的通知
+    RoomNotice { room: ID, text: String },
+    /// 被房主或管理员移出房间
+    RemovedFromRoom { room: ID, reason: String },
+    /// 按同一个时间表和这个成员同时连接
+    Punch(ClientInfo),
+}
+
+/// 让客户端按统一的时间表连接peer
+fn punch(peer: ClientInfo) -> ServerPkg {
+    ServerPkg::Punch {
+        peer,
+        delay: PUNCH_DELAY.as_millis() as u64,
+        interval: PUNCH_INTERVAL.as_millis() as u64,
+        attempts: PUNCH_ATTEMPTS,
+    }
+}
+
 #[derive(Debug)]
 struct Peer {
     user: User,
@@ -272,31 +931,62 @@ struct Peer {
     addr: SocketAddr,
     all_rooms: Arc<Mutex<AllRoomInfo>>,
     room: Vec<ID>,
-    tx: mpsc::Sender<ClientInfo>,
-    rx: mpsc::Receiver<ClientInfo>,
+    tx: mpsc::Sender<Event>,
+    rx: mpsc::Receiver<Event>,
+    stats: Arc<Stats>,
+    limiter: Arc<Limiter>,
+    // 超过这个时间没有收到数据包就断开
+    idle_timeout: Duration,
+    // 断开的原因，通知给房间内的其他成员
+    quit_reason: String,
+    // 断开后是否保留会话等待重连
+    resumable: bool,
+    // 服务器关闭，不用通知其他成员，他们之间已经建立的连接不受影响
+    shutdown: bool,
 }
 
 impl Peer {
-    fn new(user: User, stm: TcpStream, addr: SocketAddr, rooms: Arc<Mutex<AllRoomInfo>>) -> Self {
-        let (tx, rx) = mpsc::channel::<ClientInfo>(64);
+    #[allow(clippy::too_many_arguments)]
+    fn new(user: User, stm: TcpStream, addr: SocketAddr, rooms: Arc<Mutex<AllRoomInfo>>,
+        tx: mpsc::Sender<Event>, rx: mpsc::Receiver<Event>, stats: Arc<Stats>, limiter: Arc<Limiter>,
+        idle_timeout: Duration
+    ) -> Self {
         Peer {
             user, stm, addr, all_rooms: rooms,
             room: Vec::new(),
-            tx, rx,
+            tx, rx, stats, limiter, idle_timeout,
+            quit_reason: "断开连接".into(),
+            resumable: true,
+            shutdown: false,
         }
     }
 
+    /// 发送数据包并记录流量
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        write(&mut self.stm, data).await?;
+        self.stats.send(data.len());
+        Ok(())
+    }
+
     async fn poll(&mut self) -> Result<()> {
         let mut reader = TryRead::new();
+        // 第一次心跳包在HEARTBEAT之后发送，不要打断加入房间时的应答
+        let mut heartbeat = interval_at((Instant::now() + HEARTBEAT).into(), HEARTBEAT);
+        // 每收到一个数据包就重新计时
+        let idle = sleep_until((Instant::now() + self.idle_timeout).into());
+        tokio::pin!(idle);
         loop {
             tokio::select! {
                 res = self.stm.readable() => {
-                    if let Err(_) = res {
+                    if res.is_err() {
                         break;
                     }
                     match reader.poll(&mut self.stm) {
                         Ok(_) => {
-                            self.parse_pakage(&reader.package()).await?;
+                            idle.as_mut().reset((Instant::now() + self.idle_timeout).into());
+                            let pkg = reader.package();
+                            self.stats.recv(pkg.len());
+                            self.parse_pakage(&pkg).await?;
                         }
                         Err(e) => {
                             if let Some(e) = e.can_continue() {
@@ -308,137 +998,342 @@ impl Peer {
                         },
                     }
                 },
-                c = self.rx.recv() => {
-                    if let Some(c) = c {
-                        write(&mut self.stm, &serde_json::to_vec(&c)?).await?;
+                ev = self.rx.recv() => {
+                    match ev {
+                        Some(Event::Connect(room, peer)) => {
+                            self.send(&ServerPkg::PeerJoined { room, peer: peer.clone() }.package()?).await?;
+                            self.send(&punch(peer).package()?).await?;
+                        },
+                        Some(Event::Notice(text)) => {
+                            self.send(&ServerPkg::Notice(text).package()?).await?;
+                        },
+                        Some(Event::Kick(reason)) => {
+                            info!("{}: Kick {:?}，{}", self.addr, self.user, reason);
+                            self.send(&ServerPkg::Kicked(reason).package()?).await.ok();
+                            self.quit_reason = "被管理员踢出".into();
+                            self.resumable = false;
+                            break;
+                        },
+                        Some(Event::RoomClosed(rid)) => {
+                            self.room.retain(|r| *r != rid);
+                            self.send(&ServerPkg::RoomClosed(rid).package()?).await?;
+                        },
+                        Some(Event::PeerLeft { room, user, reason }) => {
+                            self.send(&ServerPkg::PeerLeft { room, user, reason }.package()?).await?;
+                        },
+                        Some(Event::RoomNotice { room, text }) => {
+                            self.send(&ServerPkg::RoomNotice { room, text }.package()?).await?;
+                        },
+                        Some(Event::RemovedFromRoom { room, reason }) => {
+                            self.room.retain(|r| *r != room);
+                            self.send(&ServerPkg::RemovedFromRoom { room, reason }.package()?).await?;
+                        },
+                        Some(Event::Punch(peer)) => {
+                            self.send(&punch(peer).package()?).await?;
+                        },
+                        Some(Event::Shutdown(reconnect)) => {
+                            self.send(&ServerPkg::Shutdown { reconnect }.package()?).await.ok();
+                            self.stm.shutdown().await.ok();
+                            self.resumable = false;
+                            self.shutdown = true;
+                            break;
+                        },
+                        None => {},
                     }
                 },
-                // 每五分钟确认一次客户端是否存在
-                _ = sleep(Duration::from_secs(5 * 60)) => {
-                    if let Err(_) = write(&mut self.stm, "".as_bytes()).await {
+                // 定时确认一次客户端是否存在
+                _ = heartbeat.tick() => {
+                    if self.send("".as_bytes()).await.is_err() {
                         break;
                     };
                 },
+                _ = &mut idle => {
+                    warn!("{}: {:?} 超过{}秒没有发送数据，断开连接",
+                            self.addr, self.user, self.idle_timeout.as_secs());
+                    self.quit_reason = "心跳超时".into();
+                    break;
+                },
             }
         }
         Ok(())
     }
 
-    async fn inst_room(&mut self, mut room: Room) -> Result<Room> {
-        let mut lock = self.all_rooms.lock().await;
-        let AllRoomInfo {
-            by_id: rooms,
-            by_name: rooms_by_name,
-            unuse_id} = &mut lock as &mut AllRoomInfo;
-        'a: loop {
-            'new_room: loop {
-                'join: loop {
-                    if room.id != 0 {
-                        if let Some(_) = rooms.get(&room.id) {
-                            break 'join;
-                        } else {
-                            break 'new_room;
-                        }
-                    } else {
-                        if let Some(rid) = rooms_by_name.get(&room.name) {
-                            room.id = *rid;
-                            break 'join;
-                        } else {
-                            if unuse_id.len() != 0 {
-                                room.id = unuse_id.pop().unwrap();
-                            } else {
-                                room.id = rooms.len() as ID;
-                                while let Some(_) = rooms.get(&room.id) {
-                                    room.id += 1;
-                                }
-                            }
-                            break 'new_room;
-                        }
-                    }
-                    // break 'a;
+    /// 加入或新建房间，被拒绝时返回None，拒绝的原因已经回复给客户端
+    async fn inst_room(&mut self, req: Room) -> Result<Option<Room>> {
+        let all_rooms = self.all_rooms.clone();
+        // 计算密码哈希很慢，不能持有所有房间的锁，先放开锁计算，之后重新检查一遍
+        // 校验过的房间密码和结果
+        let mut verified: Option<(PasswdHash, bool)> = None;
+        // 新建房间时的密码哈希
+        let mut new_hash: Option<PasswdHash> = None;
+        loop {
+            let mut room = req.clone();
+            let mut lock = all_rooms.lock().await;
+            let AllRoomInfo {
+                by_id: rooms,
+                by_name: rooms_by_name,
+                unuse_id,
+                punches} = &mut lock as &mut AllRoomInfo;
+            // 使用邀请码时找到对应的房间，不检查密码
+            let invited = !room.invite.is_empty();
+            if invited {
+                let now = Instant::now();
+                let found = rooms.values()
+                    .find(|r| r.invites.get(&room.invite).is_some_and(|i| i.until > now));
+                match found {
+                    Some(r) => {
+                        room.id = r.id;
+                        room.name = r.name.clone();
+                    },
+                    None => {
+                        drop(lock);
+                        self.send("Invalid invite".as_bytes()).await?;
+                        return Ok(None);
+                    },
                 }
-                // 加入房间
-                let r = rooms.get_mut(&room.id).unwrap();
-                if r.name == room.name && r.passwd == room.passwd {
-                    // 发送加入成功，并将完整房间信息发送过去
-                    write(&mut self.stm, "OK".as_bytes()).await?;
-                    let rom = Room {
-                        id: r.id,
-                        name: r.name.clone(),
-                        passwd: r.passwd.clone(),
-                    };
-                    write(&mut self.stm, &serde_json::to_vec(&rom)?).await?;
-
-                    let mut cis = Vec::new();
-                    let mut txs = Vec::new();
-                    for (_, client) in r.cs.iter() {
-                        let ci = ClientInfo{id: client.id, name: client.name.clone(), addr: client.addr.clone()};
-                        txs.push(client.tx.clone());
-                        cis.push(ci);
-                    }
-                    r.cs.insert(self.user.id, Client {
-                        id: self.user.id,
-                        name: self.user.name.clone(),
-                        addr: self.addr.clone(),
-                        tx: self.tx.clone(),
-                    });
-                    // 放开锁
+            }
+            // 判断是加入已有的房间还是新建房间
+            let found = match AllRoomInfo::find(rooms, rooms_by_name, &room) {
+                Ok(found) => found,
+                Err(reason) => {
                     drop(lock);
-                    // 将所有房间内的客户端发送
-                    write(&mut self.stm, &serde_json::to_vec(&cis).unwrap()).await?;
-                    // 通知房间内的其他客户端连接
-                    let cr_info = ClientInfo {
-                        id: self.user.id,
-                        name: self.user.name.clone(),
-                        addr: self.addr.clone()
-                    };
-                    for tx in txs.iter() {
-                        tx.send(cr_info.clone()).await.ok();
-                    }
+                    self.send(reason.as_bytes()).await?;
+                    return Ok(None);
+                },
+            };
+            let join = if let Some(rid) = found {
+                room.id = rid;
+                true
+            } else {
+                let max = self.limiter.limits.rooms_per_user;
+                let uid = self.user.id;
+                if max != 0 && rooms.values().filter(|r| r.owner == Some(uid)).count() >= max {
+                    drop(lock);
+                    warn!("\"{}\" 创建的房间超过{}个", self.user.name, max);
+                    Metrics::inc(&METRICS.rate_limited, 1);
+                    self.send("Too many rooms".as_bytes()).await?;
+                    return Ok(None);
+                }
+                if new_hash.is_none() {
+                    drop(lock);
+                    new_hash = Some(PasswdHash::new_blocking(room.passwd.expose().into()).await);
+                    continue;
+                }
+                if !unuse_id.is_empty() {
+                    room.id = unuse_id.pop().unwrap();
                 } else {
-                    return Err(std::io::ErrorKind::Other.into());
+                    room.id = rooms.len() as ID;
+                    while rooms.contains_key(&room.id) {
+                        room.id += 1;
+                    }
                 }
-                break 'a;
-            }
-            // 新建房间
-            let mut cs = HashMap::new();
-            cs.insert(self.user.id, Client {
+                false
+            };
+            let me = Client {
                 id: self.user.id,
                 name: self.user.name.clone(),
-                addr: self.addr.clone(),
+                addr: self.addr,
                 tx: self.tx.clone(),
-            });
-            let r = RoomFull { id: room.id, name: room.name.clone(), passwd: room.passwd.clone(), cs: cs };
-            rooms.insert(room.id, r.clone());
-            rooms_by_name.insert(room.name.clone(), room.id);
-            info!("New: {:?}", room);
-            write(&mut self.stm, "OK".as_bytes()).await?;
-            let rom = Room {
-                id: r.id,
-                name: r.name.clone(),
-                passwd: r.passwd.clone(),
             };
-            write(&mut self.stm, &serde_json::to_vec(&rom)?).await?;
-            write(&mut self.stm, &serde_json::to_vec(&Vec::<ClientInfo>::new()).unwrap()).await?;
-            break;
+            if join {
+                // 加入房间
+                let r = rooms.get_mut(&room.id).unwrap();
+                // 校验期间密码可能被修改，和校验时的不一样就重新校验
+                let passwd_ok = match &verified {
+                    _ if invited || r.name != room.name => true,
+                    Some((hash, ok)) if *hash == r.passwd => *ok,
+                    _ => {
+                        let hash = r.passwd.clone();
+                        drop(lock);
+                        let ok = hash.clone().verify_blocking(room.passwd.expose().into()).await;
+                        verified = Some((hash, ok));
+                        continue;
+                    },
+                };
+                let reject = if r.cs.contains_key(&self.user.id) {
+                    Some("Already in room")
+                } else if r.bans.contains(&self.user.name) {
+                    Some("Banned from room")
+                } else if r.name != room.name || !passwd_ok {
+                    Some("Fail to join room")
+                } else if r.capacity != 0 && r.cs.len() >= r.capacity as usize {
+                    Some("Room is full")
+                } else {
+                    None
+                };
+                if let Some(reason) = reject {
+                    drop(lock);
+                    self.send(reason.as_bytes()).await?;
+                    return Ok(None);
+                }
+                if invited && r.invites.get(&room.invite).is_some_and(|i| i.once) {
+                    r.invites.remove(&room.invite);
+                }
+                // 旧版本保存的轮数较少的哈希，密码校验通过后用现在的轮数重新计算
+                if !invited && r.passwd.outdated() {
+                    let (old, rid, passwd) = (r.passwd.clone(), r.id, room.passwd.expose().to_string());
+                    let all_rooms = all_rooms.clone();
+                    tokio::spawn(async move {
+                        let hash = PasswdHash::new_blocking(passwd).await;
+                        if let Some(r) = all_rooms.lock().await.by_id.get_mut(&rid).filter(|r| r.passwd == old) {
+                            r.passwd = hash;
+                        }
+                    });
+                }
+                let mut cis = Vec::new();
+                let mut txs = Vec::new();
+                for (_, client) in r.cs.iter() {
+                    let ci = ClientInfo{id: client.id, name: client.name.clone(), addr: client.addr};
+                    txs.push(client.tx.clone());
+                    punches.schedule(self.user.id, ci.id);
+                    cis.push(ci);
+                }
+                r.cs.insert(self.user.id, me);
+                // 常驻房间空了之后没有房主
+                if r.owner.is_none() {
+                    r.owner = Some(self.user.id);
+                }
+                let rom = r.info();
+                let roles = r.roles();
+                // 放开锁
+                drop(lock);
+                // 发送加入成功，并将完整房间信息发送过去
+                self.send("OK".as_bytes()).await?;
+                self.send(&serde_json::to_vec(&rom)?).await?;
+                // 将所有房间内的客户端发送
+                self.send(&serde_json::to_vec(&cis).unwrap()).await?;
+                self.send(&ServerPkg::RoomNotice { room: room.id, text: roles }.package()?).await?;
+                // 通知房间内的其他客户端连接，双方在同一时间开始连接
+                let cr_info = ClientInfo {
+                    id: self.user.id,
+                    name: self.user.name.clone(),
+                    addr: self.addr
+                };
+                for tx in txs.iter() {
+                    tx.send(Event::Connect(room.id, cr_info.clone())).await.ok();
+                }
+                for ci in cis {
+                    self.send(&punch(ci).package()?).await?;
+                }
+            } else {
+                // 新建房间
+                let mut cs = HashMap::new();
+                cs.insert(self.user.id, me);
+                // 创建者成为房主，常驻只能由服务器管理员设置
+                let r = RoomFull {
+                    id: room.id,
+                    name: room.name.clone(),
+                    passwd: new_hash.take().unwrap(),
+                    topic: room.topic.clone(),
+                    description: room.description.clone(),
+                    capacity: room.capacity,
+                    persistent: false,
+                    cs,
+                    owner: Some(self.user.id),
+                    mods: HashSet::new(),
+                    bans: HashSet::new(),
+                    invites: HashMap::new(),
+                };
+                let rom = r.info();
+                rooms.insert(room.id, r);
+                rooms_by_name.insert(room.name.clone(), room.id);
+                drop(lock);
+                info!("New: {:?}", room);
+                self.send("OK".as_bytes()).await?;
+                self.send(&serde_json::to_vec(&rom)?).await?;
+                self.send(&serde_json::to_vec(&Vec::<ClientInfo>::new()).unwrap()).await?;
+            }
+            // 记录房间
+            self.room.push(room.id);
+            return Ok(Some(room));
         }
-        // 记录房间
-        self.room.push(room.id);
-        Ok(room)
+    }
+
+    /// 离开房间，房间空了就删除
+    async fn leave_room(&mut self, rid: ID) -> std::result::Result<String, String> {
+        if !self.room.contains(&rid) {
+            return Err("你不在这个房间中".into());
+        }
+        self.room.retain(|r| *r != rid);
+        let name = self.all_rooms.lock().await.by_id.get(&rid).map(|r| r.name.clone()).unwrap_or_default();
+        let user = BaseUserInfo { id: self.user.id, name: self.user.name.clone() };
+        CertificationCenter::leave_rooms(&self.all_rooms, &user, &[rid], "离开房间").await;
+        info!("\"{}\" leave \"{}\"", self.user.name, name);
+        Ok(format!("已离开房间{}", name))
+    }
+
+    /// 客户端和peer的连接断开了，通知双方同时重新连接，只在双方还有共同的房间时才协调
+    async fn redial(&mut self, peer: ID) -> Result<()> {
+        let other = {
+            let mut lock = self.all_rooms.lock().await;
+            let other = self.room.iter()
+                .filter_map(|rid| lock.by_id.get(rid))
+                .find_map(|r| r.cs.get(&peer).filter(|_| peer != self.user.id).cloned());
+            if other.is_some() {
+                lock.punches.schedule(self.user.id, peer);
+            }
+            other
+        };
+        let other = match other {
+            Some(other) => other,
+            None => {
+                debug!("\"{}\" 请求重新连接的成员{}不在同一个房间中", self.user.name, peer);
+                return Ok(());
+            },
+        };
+        info!("协调\"{}\"和\"{}\"重新连接", self.user.name, other.name);
+        let me = ClientInfo { id: self.user.id, name: self.user.name.clone(), addr: self.addr };
+        let ci = ClientInfo { id: other.id, name: other.name, addr: other.addr };
+        other.tx.send(Event::Punch(me)).await.ok();
+        self.send(&punch(ci).package()?).await
     }
 
     // 处理数据包
     async fn parse_pakage(&mut self, pkg: &[u8]) -> Result<()> {
-        if let Ok(room) = serde_json::from_slice::<net::Room>(&pkg) {
+        if let Ok(room) = serde_json::from_slice::<net::Room>(pkg) {
+            if !self.limiter.join(self.user.id) {
+                self.send("Too many joins".as_bytes()).await?;
+                return Ok(());
+            }
             // 接收客户端传过来的房间信息
-            let room = match self.inst_room(room).await {
-                Ok(rom) => { rom },
-                Err(_) => {
-                    write(&mut self.stm, "Fail to join room".as_bytes()).await?;
-                    return Ok(());
-                }
+            let room = match self.inst_room(room).await? {
+                Some(rom) => { rom },
+                None => return Ok(()),
             };
+            METRICS.joined();
             info!("\"{}\" join \"{}\"", self.user.name, room.name);
+        } else if let Ok(cmd) = serde_json::from_slice::<RoomCmd>(pkg) {
+            let user = BaseUserInfo { id: self.user.id, name: self.user.name.clone() };
+            let reply = match moderation::exec(&self.all_rooms, &user, cmd).await {
+                Ok(text) => ServerPkg::CmdReply { ok: true, text },
+                Err(text) => ServerPkg::CmdReply { ok: false, text },
+            };
+            self.send(&reply.package()?).await?;
+        } else if let Ok(leave) = serde_json::from_slice::<LeaveRoom>(pkg) {
+            let reply = match self.leave_room(leave.leave).await {
+                Ok(text) => ServerPkg::CmdReply { ok: true, text },
+                Err(text) => ServerPkg::CmdReply { ok: false, text },
+            };
+            self.send(&reply.package()?).await?;
+        } else if let Ok(req) = serde_json::from_slice::<Redial>(pkg) {
+            self.redial(req.redial).await?;
+        } else if let Ok(report) = serde_json::from_slice::<PunchReport>(pkg) {
+            // 双方都会报告，只统计先到的那个，没有安排过的不统计
+            if !self.all_rooms.lock().await.punches.report(self.user.id, report.peer) {
+                debug!("\"{}\" 和User[id: {}]的连接结果已经统计过或者没有安排过，忽略", self.user.name, report.peer);
+            } else if report.ok {
+                Metrics::inc(&METRICS.punch_successes, 1);
+                info!("\"{}\" 已连接User[id: {}]", self.user.name, report.peer);
+            } else {
+                Metrics::inc(&METRICS.punch_failures, 1);
+                warn!("\"{}\" 无法连接User[id: {}]", self.user.name, report.peer);
+            }
+        } else if let Ok(cis) = serde_json::from_slice::<Vec<ClientInfo>>(pkg) {
+            // 旧版本的客户端加入房间后报告没能连接上的成员
+            if !cis.is_empty() {
+                Metrics::inc(&METRICS.punch_failures, cis.len() as u64);
+                warn!("\"{}\" 无法连接：{:?}", self.user.name, cis);
+            }
         }
         Ok(())
     }
@@ -449,7 +1344,7 @@ struct Client {
     id: ID,
     name: String,
     addr: SocketAddr,
-    tx: mpsc::Sender<ClientInfo>,
+    tx: mpsc::Sender<Event>,
 }
 
 impl Debug for Client {
@@ -464,6 +1359,112 @@ impl Debug for Client {
 pub struct RoomFull {
     pub id: ID,
     name: String,
-    passwd: String,
+    passwd: PasswdHash,
+    topic: String,
+    description: String,
+    // 最多容纳的人数，0为不限
+    capacity: u32,
+    // 没有成员时也保留
+    persistent: bool,
     cs: HashMap<ID, Client>,
+    // 房主，创建房间的人，常驻房间空了之后为None
+    owner: Option<ID>,
+    // 管理员
+    mods: HashSet<ID>,
+    // 禁止进入房间的用户名
+    bans: HashSet<String>,
+    // 邀请码
+    invites: HashMap<Secret, Invite>,
+}
+
+/// 房间的邀请码，持有者不需要密码就可以加入
+#[derive(Debug, Clone)]
+struct Invite {
+    until: Instant,
+    // 只能使用一次
+    once: bool,
+}
+
+impl RoomFull {
+    /// 发给客户端的房间信息
+    fn info(&self) -> Room {
+        Room {
+            id: self.id,
+            name: self.name.clone(),
+            passwd: Secret::default(),
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            capacity: self.capacity,
+            persistent: self.persistent,
+            invite: Secret::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn room(id: ID, name: &str) -> RoomFull {
+        RoomFull {
+            id,
+            name: name.into(),
+            passwd: PasswdHash::try_from(format!("pbkdf2-sha256$10000${}${}", "0".repeat(32), "0".repeat(64))).unwrap(),
+            topic: String::new(),
+            description: String::new(),
+            capacity: 0,
+            persistent: false,
+            cs: HashMap::new(),
+            owner: None,
+            mods: HashSet::new(),
+            bans: HashSet::new(),
+            invites: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn find_room() {
+        let mut all = AllRoomInfo::new();
+        all.by_id.insert(3, room(3, "r"));
+        all.by_name.insert("r".into(), 3);
+        let find = |id, name: &str| {
+            AllRoomInfo::find(&all.by_id, &all.by_name, &Room { id, name: name.into(), ..Default::default() })
+        };
+        assert_eq!(find(0, "r"), Ok(Some(3)));
+        assert_eq!(find(3, "other"), Ok(Some(3)));
+        // 新建房间只能按名字，不能由客户端指定id
+        assert_eq!(find(0, "new"), Ok(None));
+        assert_eq!(find(7, "new"), Err("Room does not exist"));
+        let invite = Room { invite: "x".into(), ..Default::default() };
+        assert_eq!(AllRoomInfo::find(&all.by_id, &all.by_name, &invite), Err("Room does not exist"));
+    }
+
+    fn conn() -> Conn {
+        let (tx, _) = mpsc::channel(1);
+        Conn { addr: "127.0.0.1:1".parse().unwrap(), tx, stats: Arc::default(), since: Instant::now() }
+    }
+
+    #[test]
+    fn detach_and_resume() {
+        let mut users = AllUserInfo::default();
+        let mut alice = User { id: 0, name: "alice".into(), passwd: "pw".into() };
+        users.insert(&mut alice, conn());
+        users.detach("token".into(), Detached { user: alice.clone(), rooms: vec![3], members: HashMap::new() });
+        // 断线期间用户名仍然被占用，但不再有连接
+        assert!(!users.conns.contains_key(&alice.id));
+        assert_eq!(users.by_name.get("alice"), Some(&alice.id));
+        let mut bob = User { id: 0, name: "bob".into(), passwd: "pw".into() };
+        users.insert(&mut bob, conn());
+        assert_ne!(bob.id, alice.id);
+        // 用token恢复后沿用原来的id和房间
+        let d = users.detached.remove(&Secret::from("token")).unwrap();
+        assert_eq!((d.user.id, d.rooms), (alice.id, vec![3]));
+        assert!(!users.detached.contains_key(&Secret::from("token")));
+        // 会话过期后id和用户名才释放
+        users.remove(alice.id);
+        assert!(!users.by_name.contains_key("alice"));
+        let mut carol = User { id: 0, name: "carol".into(), passwd: "pw".into() };
+        users.insert(&mut carol, conn());
+        assert_eq!(carol.id, alice.id);
+    }
 }
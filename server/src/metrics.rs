@@ -0,0 +1,125 @@
+use std::{collections::VecDeque, sync::Mutex, time::{Duration, Instant}};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 计算每秒加入房间次数的时间窗口
+const JOIN_WINDOW: Duration = Duration::from_secs(60);
+
+/// 全局的计数器，在/metrics中输出
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// 成功加入房间的次数
+    joins: AtomicU64,
+    /// 最近JOIN_WINDOW内加入房间的时间
+    recent_joins: Mutex<VecDeque<Instant>>,
+    /// 登录失败的次数
+    pub login_failures: AtomicU64,
+    /// 客户端报告的打洞成功和失败次数
+    pub punch_successes: AtomicU64,
+    pub punch_failures: AtomicU64,
+    /// 因为超过限制被拒绝的次数
+    pub rate_limited: AtomicU64,
+    pub pkgs_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub pkgs_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            joins: AtomicU64::new(0),
+            recent_joins: Mutex::new(VecDeque::new()),
+            login_failures: AtomicU64::new(0),
+            punch_successes: AtomicU64::new(0),
+            punch_failures: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            pkgs_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            pkgs_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// 成功加入了一次房间
+    pub fn joined(&self) {
+        Self::inc(&self.joins, 1);
+        let now = Instant::now();
+        let mut recent = self.recent_joins.lock().unwrap();
+        Self::expire(&mut recent, now);
+        recent.push_back(now);
+    }
+
+    /// 最近一分钟平均每秒加入房间的次数
+    pub fn joins_per_second(&self) -> f64 {
+        let mut recent = self.recent_joins.lock().unwrap();
+        Self::expire(&mut recent, Instant::now());
+        recent.len() as f64 / JOIN_WINDOW.as_secs_f64()
+    }
+
+    fn expire(recent: &mut VecDeque<Instant>, now: Instant) {
+        while recent.front().is_some_and(|t| now.duration_since(*t) >= JOIN_WINDOW) {
+            recent.pop_front();
+        }
+    }
+
+    /// Prometheus文本格式，gauges为(名称, 说明, 值)
+    pub fn render(&self, gauges: &[(&str, &str, f64)]) -> String {
+        let counters = [
+            ("chat_room_joins_total", "成功加入房间的次数", &self.joins),
+            ("chat_login_failures_total", "登录失败的次数", &self.login_failures),
+            ("chat_punch_successes_total", "客户端报告的打洞成功次数", &self.punch_successes),
+            ("chat_punch_failures_total", "客户端报告的打洞失败次数", &self.punch_failures),
+            ("chat_rate_limited_total", "因为超过限制被拒绝的次数", &self.rate_limited),
+            ("chat_packets_received_total", "从客户端收到的数据包数", &self.pkgs_in),
+            ("chat_bytes_received_total", "从客户端收到的字节数", &self.bytes_in),
+            ("chat_packets_sent_total", "发给客户端的数据包数", &self.pkgs_out),
+            ("chat_bytes_sent_total", "发给客户端的字节数", &self.bytes_out),
+        ];
+        let mut out = String::new();
+        let joins_per_second = ("chat_room_joins_per_second", "最近一分钟平均每秒加入房间的次数", self.joins_per_second());
+        for (name, help, value) in gauges.iter().chain([&joins_per_second]) {
+            out += &format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value);
+        }
+        for (name, help, value) in counters {
+            out += &format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                    name, help, name, name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        for _ in 0..3 {
+            metrics.joined();
+        }
+        Metrics::inc(&metrics.login_failures, 2);
+        let out = metrics.render(&[("chat_online_users", "在线用户数", 4.0)]);
+        assert!(out.contains("# TYPE chat_online_users gauge\nchat_online_users 4\n"));
+        assert!(out.contains("\nchat_room_joins_total 3\n"));
+        assert!(out.contains("\nchat_room_joins_per_second 0.05\n"));
+        assert!(out.contains("\nchat_login_failures_total 2\n"));
+    }
+
+    #[test]
+    fn joins_expire() {
+        let metrics = Metrics::new();
+        metrics.joined();
+        // 一分钟以前的不再计入
+        metrics.recent_joins.lock().unwrap().push_front(Instant::now() - JOIN_WINDOW);
+        assert_eq!(metrics.joins_per_second(), 1.0 / 60.0);
+        assert_eq!(metrics.recent_joins.lock().unwrap().len(), 1);
+        assert_eq!(metrics.joins.load(Ordering::Relaxed), 1);
+    }
+}
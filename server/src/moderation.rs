@@ -0,0 +1,277 @@
+//! 房主和管理员对房间的管理
+
+use std::{sync::Arc, time::{Duration, Instant}};
+use log::info;
+use net::{BaseUserInfo, RoomCmd, RoomOp, ID};
+use tokio::sync::{mpsc, Mutex};
+use super::{passwd::PasswdHash, AllRoomInfo, Event, Invite, RoomFull};
+
+/// 成员在房间内的角色，只能管理角色比自己低的成员
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+// 邀请码最长的有效期
+const MAX_INVITE_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+type Notify = Vec<(mpsc::Sender<Event>, Event)>;
+
+impl RoomFull {
+    pub fn role(&self, id: ID) -> Role {
+        if self.owner == Some(id) {
+            Role::Owner
+        } else if self.mods.contains(&id) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
+    }
+
+    /// 按用户名查找房间内的成员
+    fn member(&self, name: &str) -> Result<ID, String> {
+        self.cs.values()
+            .find(|c| c.name == name)
+            .map(|c| c.id)
+            .ok_or(format!("房间中没有{}", name))
+    }
+
+    /// 通知房间内的所有成员
+    fn notice(&self, text: String, notify: &mut Notify) {
+        for c in self.cs.values() {
+            notify.push((c.tx.clone(), Event::RoomNotice { room: self.id, text: text.clone() }));
+        }
+    }
+
+    /// 将成员移出房间，通知本人和其他成员
+    fn remove_member(&mut self, id: ID, reason: String, notify: &mut Notify) {
+        let c = match self.cs.remove(&id) {
+            Some(c) => c,
+            None => return,
+        };
+        self.mods.remove(&id);
+        info!("User[id: {}, name: \"{}\"] removed from Room[id: {}, name: \"{}\"]: {}",
+                c.id, c.name, self.id, self.name, reason);
+        notify.push((c.tx.clone(), Event::RemovedFromRoom { room: self.id, reason: reason.clone() }));
+        let user = BaseUserInfo { id: c.id, name: c.name };
+        for c in self.cs.values() {
+            notify.push((c.tx.clone(), Event::PeerLeft { room: self.id, user: user.clone(), reason: reason.clone() }));
+        }
+    }
+
+    /// 房主离开后交给ID最小的管理员，没有管理员时交给ID最小的成员
+    /// 房间空了就没有房主，下一个加入的人成为房主
+    /// 返回新房主的ID
+    pub(crate) fn pick_owner(&mut self, notify: &mut Notify) -> Option<ID> {
+        self.owner = None;
+        let owner = self.mods.iter().min().copied()
+            .or_else(|| self.cs.keys().min().copied())?;
+        self.owner = Some(owner);
+        self.mods.remove(&owner);
+        self.notice(format!("{}成为了新房主", self.cs[&owner].name), notify);
+        Some(owner)
+    }
+
+    /// 加入房间时告诉新成员房主和管理员
+    pub fn roles(&self) -> String {
+        let name = |id: &ID| self.cs.get(id).map_or("无".into(), |c| c.name.clone());
+        let owner = self.owner.as_ref().map_or("无".into(), name);
+        let mut mods: Vec<String> = self.mods.iter().map(name).collect();
+        mods.sort();
+        if mods.is_empty() {
+            format!("房主：{}", owner)
+        } else {
+            format!("房主：{}，管理员：{}", owner, mods.join(", "))
+        }
+    }
+}
+
+/// 执行房间管理指令，返回给发送者的结果
+pub async fn exec(rooms: &Arc<Mutex<AllRoomInfo>>, user: &BaseUserInfo, cmd: RoomCmd) -> Result<String, String> {
+    let mut notify = Vec::new();
+    // 新密码的哈希计算很慢，先确认有权限，在拿到锁之前算好，之后apply还会再检查一遍
+    let hash = match &cmd.op {
+        RoomOp::Passwd(passwd) => {
+            match rooms.lock().await.by_id.get(&cmd.room) {
+                Some(room) if room.cs.contains_key(&user.id) => permitted(room, user.id, &cmd.op)?,
+                _ => return Err("你不在这个房间中".into()),
+            };
+            Some(PasswdHash::new_blocking(passwd.expose().into()).await)
+        },
+        _ => None,
+    };
+    let res = {
+        let mut lock = rooms.lock().await;
+        match lock.by_id.get_mut(&cmd.room) {
+            Some(room) if room.cs.contains_key(&user.id) => apply(room, user, cmd.op, hash, &mut notify),
+            _ => Err("你不在这个房间中".into()),
+        }
+    };
+    for (tx, ev) in notify {
+        tx.send(ev).await.ok();
+    }
+    res
+}
+
+/// 检查成员是否有权限执行指令，返回成员的角色
+fn permitted(room: &RoomFull, id: ID, op: &RoomOp) -> Result<Role, String> {
+    let me = room.role(id);
+    let need = match op {
+        RoomOp::Kick { .. } | RoomOp::Ban { .. } | RoomOp::Unban { .. } | RoomOp::Topic(_) => Role::Moderator,
+        _ => Role::Owner,
+    };
+    if me < need {
+        return Err("没有权限".into());
+    }
+    Ok(me)
+}
+
+fn apply(room: &mut RoomFull, user: &BaseUserInfo, op: RoomOp, hash: Option<PasswdHash>,
+        notify: &mut Notify) -> Result<String, String> {
+    let me = permitted(room, user.id, &op)?;
+    // 只能管理角色比自己低的成员
+    let outranks = |room: &RoomFull, id: ID| -> Result<(), String> {
+        if room.role(id) >= me {
+            Err("不能管理角色不低于自己的成员".into())
+        } else {
+            Ok(())
+        }
+    };
+    match op {
+        RoomOp::Kick { user: name, reason } => {
+            let target = room.member(&name)?;
+            outranks(room, target)?;
+            let reason = if reason.is_empty() {
+                format!("被{}踢出", user.name)
+            } else {
+                format!("被{}踢出：{}", user.name, reason)
+            };
+            room.remove_member(target, reason, notify);
+            Ok(format!("已将{}移出房间", name))
+        },
+        RoomOp::Ban { user: name } => {
+            if let Ok(target) = room.member(&name) {
+                outranks(room, target)?;
+                room.remove_member(target, format!("被{}禁止进入房间", user.name), notify);
+            }
+            room.bans.insert(name.clone());
+            Ok(format!("已禁止{}进入房间", name))
+        },
+        RoomOp::Unban { user: name } => {
+            if room.bans.remove(&name) {
+                Ok(format!("已允许{}进入房间", name))
+            } else {
+                Err(format!("{}没有被禁止进入房间", name))
+            }
+        },
+        RoomOp::Passwd(_) => {
+            room.passwd = hash.ok_or("无法设置密码")?;
+            info!("Room[id: {}, name: \"{}\"] password changed by \"{}\"", room.id, room.name, user.name);
+            Ok("房间密码已修改".into())
+        },
+        RoomOp::Op { user: name } => {
+            let target = room.member(&name)?;
+            if room.role(target) != Role::Member {
+                return Err(format!("{}已经是管理员", name));
+            }
+            room.mods.insert(target);
+            room.notice(format!("{}成为了管理员", name), notify);
+            Ok(format!("已将{}设为管理员", name))
+        },
+        RoomOp::Deop { user: name } => {
+            let target = room.member(&name)?;
+            if !room.mods.remove(&target) {
+                return Err(format!("{}不是管理员", name));
+            }
+            room.notice(format!("{}不再是管理员", name), notify);
+            Ok(format!("已取消{}的管理员", name))
+        },
+        RoomOp::Transfer { user: name } => {
+            let target = room.member(&name)?;
+            if target == user.id {
+                return Err("你已经是房主".into());
+            }
+            room.owner = Some(target);
+            room.mods.remove(&target);
+            room.mods.insert(user.id);
+            room.notice(format!("{}将房主转让给了{}", user.name, name), notify);
+            Ok(format!("已将房主转让给{}", name))
+        },
+        RoomOp::Topic(topic) => {
+            room.topic = topic;
+            if room.topic.is_empty() {
+                room.notice(format!("{}清除了话题", user.name), notify);
+            } else {
+                room.notice(format!("{}将话题改为：{}", user.name, room.topic), notify);
+            }
+            Ok("话题已修改".into())
+        },
+        RoomOp::Describe(description) => {
+            room.description = description;
+            Ok("房间介绍已修改".into())
+        },
+        RoomOp::Capacity(capacity) => {
+            room.capacity = capacity;
+            if capacity == 0 {
+                Ok("已取消人数上限".into())
+            } else if room.cs.len() > capacity as usize {
+                // 已经在房间里的人不受影响
+                Ok(format!("人数上限已改为{}，当前有{}人，暂时不能加入", capacity, room.cs.len()))
+            } else {
+                Ok(format!("人数上限已改为{}", capacity))
+            }
+        },
+        RoomOp::Invite { ttl, once } => {
+            if ttl == 0 {
+                return Err("邀请码有效期必须大于0".into());
+            }
+            let now = Instant::now();
+            let until = Some(Duration::from_secs(ttl))
+                .filter(|ttl| *ttl <= MAX_INVITE_TTL)
+                .and_then(|ttl| now.checked_add(ttl))
+                .ok_or("邀请码有效期太长")?;
+            room.invites.retain(|_, i| i.until > now);
+            let token = format!("{:032x}", rand::random::<u128>());
+            room.invites.insert(token.as_str().into(), Invite { until, once });
+            info!("Room[id: {}, name: \"{}\"] invite created by \"{}\", ttl {}s{}",
+                    room.id, room.name, user.name, ttl, if once { ", once" } else { "" });
+            Ok(format!("邀请码：{}（{}秒内有效{}）", token, ttl, if once { "，只能使用一次" } else { "" }))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(room: &mut RoomFull, ttl: u64) -> Result<String, String> {
+        let user = BaseUserInfo { id: 1, name: "alice".into() };
+        apply(room, &user, RoomOp::Invite { ttl, once: false }, None, &mut Vec::new())
+    }
+
+    #[test]
+    fn invite_ttl() {
+        let mut room = crate::tests::room(0, "r");
+        room.owner = Some(1);
+        assert_eq!(invite(&mut room, 0), Err("邀请码有效期必须大于0".into()));
+        assert_eq!(invite(&mut room, u64::MAX), Err("邀请码有效期太长".into()));
+        assert_eq!(invite(&mut room, MAX_INVITE_TTL.as_secs() + 1), Err("邀请码有效期太长".into()));
+        assert!(room.invites.is_empty());
+        assert!(invite(&mut room, 60).unwrap().contains("60秒内有效"));
+        let until = room.invites.values().next().unwrap().until;
+        assert!(until > Instant::now() + Duration::from_secs(59));
+        // 创建新的邀请码时清除过期的
+        room.invites.values_mut().for_each(|i| i.until = Instant::now());
+        invite(&mut room, 60).unwrap();
+        assert_eq!(room.invites.len(), 1);
+    }
+
+    #[test]
+    fn invite_needs_owner() {
+        let mut room = crate::tests::room(0, "r");
+        room.mods.insert(1);
+        assert_eq!(invite(&mut room, 60), Err("没有权限".into()));
+    }
+}
@@ -0,0 +1,159 @@
+//! 房间密码只保存加盐的哈希，保存到状态文件时格式为`pbkdf2-sha256$轮数$盐$哈希`
+
+use std::fmt::{self, Debug, Write};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// PBKDF2的迭代轮数
+const ROUNDS: u32 = 600_000;
+// 状态文件中可以接受的轮数，旧版本用10000轮，太大的轮数会让每次加入房间都占满CPU
+const MIN_ROUNDS: u32 = 10_000;
+const MAX_ROUNDS: u32 = 10_000_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const SCHEME: &str = "pbkdf2-sha256";
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PasswdHash {
+    rounds: u32,
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+}
+
+impl PasswdHash {
+    pub fn new(passwd: &str) -> Self {
+        Self::with_rounds(passwd, ROUNDS)
+    }
+
+    fn with_rounds(passwd: &str, rounds: u32) -> Self {
+        let salt = rand::random();
+        Self { rounds, salt, hash: derive(passwd, &salt, rounds) }
+    }
+
+    /// 用常数时间比较，不会因为比较提前结束泄露哈希的内容
+    pub fn verify(&self, passwd: &str) -> bool {
+        let hash = derive(passwd, &self.salt, self.rounds);
+        hash.iter().zip(self.hash.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    /// PBKDF2很慢，在处理连接的task中放到阻塞线程里计算
+    pub async fn new_blocking(passwd: String) -> Self {
+        tokio::task::spawn_blocking(move || Self::new(&passwd)).await.expect("计算密码哈希的线程崩溃")
+    }
+
+    /// 轮数比现在的少，校验通过后应该重新计算
+    pub fn outdated(&self) -> bool {
+        self.rounds < ROUNDS
+    }
+
+    pub async fn verify_blocking(self, passwd: String) -> bool {
+        tokio::task::spawn_blocking(move || self.verify(&passwd)).await.unwrap_or(false)
+    }
+}
+
+fn derive(passwd: &str, salt: &[u8], rounds: u32) -> [u8; HASH_LEN] {
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passwd.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+impl Debug for PasswdHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswdHash(***)")
+    }
+}
+
+impl From<PasswdHash> for String {
+    fn from(h: PasswdHash) -> Self {
+        format!("{}${}${}${}", SCHEME, h.rounds, to_hex(&h.salt), to_hex(&h.hash))
+    }
+}
+
+impl TryFrom<String> for PasswdHash {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = s.split('$').collect();
+        match parts[..] {
+            [SCHEME, rounds, salt, hash] => Ok(Self {
+                rounds: rounds.parse().ok()
+                    .filter(|r| (MIN_ROUNDS..=MAX_ROUNDS).contains(r))
+                    .ok_or("无法识别的轮数")?,
+                salt: from_hex(salt).ok_or("无法识别的盐")?,
+                hash: from_hex(hash).ok_or("无法识别的哈希")?,
+            }),
+            _ => Err("无法识别的密码哈希格式".into()),
+        }
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(s, "{:02x}", b).ok();
+    }
+    s
+}
+
+fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 调试版本中计算600000轮很慢，测试用最少的轮数
+    fn quick(passwd: &str) -> PasswdHash {
+        PasswdHash::with_rounds(passwd, MIN_ROUNDS)
+    }
+
+    #[test]
+    fn verify() {
+        let hash = quick("secret");
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("Secret"));
+        assert!(!hash.verify(""));
+        // 每次的盐不同
+        assert_ne!(quick("secret"), hash);
+    }
+
+    #[test]
+    fn string_round_trip() {
+        let hash = quick("secret");
+        let s = String::from(hash.clone());
+        assert!(s.starts_with("pbkdf2-sha256$10000$"));
+        let parsed = PasswdHash::try_from(s).unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify("secret"));
+        let json = serde_json::to_string(&hash).unwrap();
+        assert!(serde_json::from_str::<PasswdHash>(&json).unwrap().verify("secret"));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let salt = "00".repeat(SALT_LEN);
+        let hash = "00".repeat(HASH_LEN);
+        for s in ["", "plain", "md5$1$00$00", "pbkdf2-sha256$x$00$00", "pbkdf2-sha256$1$zz$00",
+                &format!("pbkdf2-sha256$0${}${}", salt, hash),
+                &format!("pbkdf2-sha256$4294967295${}${}", salt, hash)] {
+            assert!(PasswdHash::try_from(s.to_string()).is_err(), "{}", s);
+        }
+        assert_eq!(format!("{:?}", quick("secret")), "PasswdHash(***)");
+    }
+
+    #[test]
+    fn outdated() {
+        // 旧版本保存的10000轮的哈希还能读取和校验，但需要重新计算
+        assert!(quick("secret").outdated());
+        assert!(!PasswdHash { rounds: ROUNDS, salt: [0; SALT_LEN], hash: [0; HASH_LEN] }.outdated());
+    }
+}
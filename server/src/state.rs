@@ -0,0 +1,74 @@
+//! 服务器关闭时保存、启动时恢复的状态
+
+use std::{io, path::Path};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use net::ID;
+use super::{admin::BanTarget, passwd::PasswdHash};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub bans: Vec<SavedBan>,
+    #[serde(default)]
+    pub rooms: Vec<SavedRoom>,
+}
+
+/// 一条封禁，until为解封时间的毫秒时间戳，None为永久
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedBan {
+    pub target: BanTarget,
+    pub until: Option<i64>,
+}
+
+/// 常驻房间，恢复后没有成员和房主
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedRoom {
+    pub id: ID,
+    pub name: String,
+    #[serde(default)]
+    pub passwd_hash: Option<PasswdHash>,
+    /// 旧版本保存的明文密码，读取后转换为哈希
+    #[serde(default, skip_serializing)]
+    pub passwd: Option<String>,
+    #[serde(default)]
+    pub topic: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub capacity: u32,
+    /// 禁止进入房间的用户名
+    #[serde(default)]
+    pub bans: Vec<String>,
+}
+
+impl State {
+    /// 文件不存在时返回空的状态
+    pub fn load(path: &Path) -> Self {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("无法读取{}：{}", path.display(), e);
+                return Self::default();
+            },
+        };
+        match serde_json::from_slice(&data) {
+            Ok(state) => {
+                info!("已从{}恢复服务器状态", path.display());
+                state
+            },
+            Err(e) => {
+                warn!("{}格式错误：{}", path.display(), e);
+                Self::default()
+            },
+        }
+    }
+
+    /// 先写到临时文件再改名，避免写到一半时退出导致文件损坏
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)
+    }
+}